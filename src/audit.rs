@@ -0,0 +1,84 @@
+use chrono::{DateTime, UTC};
+use errors::*;
+use serde_json;
+use std::fs::{self, File, OpenOptions};
+use std::io::BufReader;
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+
+pub const EVENT_APPROVED: &'static str = "approved";
+pub const EVENT_REVOKED: &'static str = "revoked";
+pub const EVENT_TEST_STARTED: &'static str = "test_started";
+pub const EVENT_TEST_FINISHED: &'static str = "test_finished";
+pub const EVENT_PUSHED: &'static str = "pushed";
+pub const EVENT_PUSH_FAILED: &'static str = "push_failed";
+
+/// A line of the audit log
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Record {
+    pub time: DateTime<UTC>,
+    pub event: String,
+    pub repo: String,
+    pub project: String,
+    pub merge_request: u64,
+    pub merge_request_iid: u64,
+    pub sha: String,
+    pub target_branch: String,
+    pub approver: Option<String>,
+    pub approved_at: Option<DateTime<UTC>>,
+    pub source_sha: Option<String>,
+    pub target_sha: Option<String>,
+    pub merge_sha: Option<String>,
+    pub result: Option<String>,
+}
+
+/// Append-only audit log in the JSON Lines format
+#[derive(Debug)]
+pub struct AuditLog {
+    path: PathBuf,
+}
+
+impl AuditLog {
+    pub fn new(path: &Path) -> Result<Self> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        Ok(AuditLog { path: path.into() })
+    }
+
+    pub fn append(&self, record: &Record) -> Result<()> {
+        let mut line = serde_json::to_string(record)?;
+        line.push('\n');
+
+        // Each record is written at once so that lines from other processes are not interleaved
+        let mut file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        file.write_all(line.as_bytes())?;
+        Ok(())
+    }
+}
+
+/// Reads the records of the repository (label or project path) written since the given time.
+pub fn query(path: &Path, repo: Option<&str>, since: Option<DateTime<UTC>>) -> Result<Vec<Record>> {
+    let file = File::open(path)
+        .chain_err(|| format!("failed to open audit log: {}", path.to_string_lossy()))?;
+
+    let mut records = vec![];
+    for (i, line) in BufReader::new(file).lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let record: Record = serde_json::from_str(&line)
+            .chain_err(|| format!("invalid audit record at line {}", i + 1))?;
+
+        if repo.map_or(false, |repo| repo != record.repo && repo != record.project) {
+            continue;
+        }
+        if since.map_or(false, |since| record.time < since) {
+            continue;
+        }
+        records.push(record);
+    }
+
+    Ok(records)
+}
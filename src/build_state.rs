@@ -1,10 +1,11 @@
 use chrono::{DateTime, UTC};
 use errors::*;
-use gitlab::{CommitStatus, CommitStatusInfo, ObjectId, ProjectId, StatusState};
+use gitlab::{CommitStatus, CommitStatusInfo, MergeRequestId, ObjectId, ProjectId, StatusState};
 use gitlab_ext::GitlabExt;
 use serde_json;
 use slog;
 use std::cmp::Ordering;
+use std::marker::PhantomData;
 
 pub trait State
     where Self: Sized
@@ -96,11 +97,13 @@ impl slog::Serialize for ApprovalKind {
             ApprovalKind::NotApproved => serializer.emit_str(key, self.as_str()),
             ApprovalKind::Approved { ref info, .. } => {
                 serializer.emit_arguments(key,
-                                          &format_args!("{}(p={},date={},approved_by={})",
+                                          &format_args!("{}(p={},date={},approved_by={},\
+                                                         rollup={})",
                                                         self.as_str(),
                                                         info.priority,
                                                         info.time,
-                                                        info.username))
+                                                        info.username,
+                                                        info.rollup.as_str()))
             }
         }
     }
@@ -151,11 +154,36 @@ impl ApprovalKind {
     }
 }
 
+#[derive(Debug, Copy, Clone, Serialize, Deserialize, Eq, PartialEq, Ord, PartialOrd)]
+pub enum Rollup {
+    Never,
+    Maybe,
+    Always,
+}
+
+impl Default for Rollup {
+    fn default() -> Self {
+        Rollup::Maybe
+    }
+}
+
+impl Rollup {
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            Rollup::Never => "never",
+            Rollup::Maybe => "maybe",
+            Rollup::Always => "always",
+        }
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize, Eq, PartialEq)]
 pub struct ApprovalInfo {
     pub priority: u64,
     pub time: DateTime<UTC>,
     pub username: String,
+    #[serde(default)]
+    pub rollup: Rollup,
 }
 
 impl Ord for ApprovalInfo {
@@ -164,6 +192,7 @@ impl Ord for ApprovalInfo {
             .cmp(&other.priority)
             .then_with(|| self.time.cmp(&other.time).reverse())
             .then_with(|| self.username.cmp(&other.username).reverse())
+            .then_with(|| self.rollup.cmp(&other.rollup))
     }
 }
 
@@ -267,6 +296,8 @@ impl State for Approval {
 #[derive(Debug, Eq, PartialEq)]
 pub enum TestKind {
     Pending,
+    /// Waiting for re-test after the rollup described by `info` failed
+    Bisecting { desc: String, info: TestInfo },
     Running { desc: String, info: TestInfo },
     Success { desc: String, info: TestInfo },
     Failed(Option<(String, TestInfo)>),
@@ -282,6 +313,7 @@ impl slog::Serialize for TestKind {
         match *self {
             TestKind::Pending |
             TestKind::Failed(None) => serializer.emit_str(key, self.as_str()),
+            TestKind::Bisecting { ref info, .. } |
             TestKind::Running { ref info, .. } |
             TestKind::Success { ref info, .. } |
             TestKind::Failed(Some((_, ref info))) |
@@ -296,6 +328,14 @@ impl slog::Serialize for TestKind {
 }
 
 impl TestKind {
+    pub fn new_bisecting(info: TestInfo) -> Result<Self> {
+        let desc = serde_json::to_string(&info)?;
+        Ok(TestKind::Bisecting {
+            desc: desc,
+            info: info,
+        })
+    }
+
     pub fn new_running(info: TestInfo) -> Result<Self> {
         let desc = serde_json::to_string(&info)?;
         Ok(TestKind::Running {
@@ -329,6 +369,7 @@ impl TestKind {
         match *self {
             TestKind::Pending |
             TestKind::Failed(None) => None,
+            TestKind::Bisecting { ref info, .. } |
             TestKind::Running { ref info, .. } |
             TestKind::Success { ref info, .. } |
             TestKind::Failed(Some((_, ref info))) |
@@ -337,7 +378,7 @@ impl TestKind {
     }
 
     fn from_commit_status(commit_status: &CommitStatus) -> Result<Self> {
-        if commit_status.status == StatusState::Pending {
+        if commit_status.status == StatusState::Pending && commit_status.description.is_none() {
             return Ok(TestKind::Pending);
         }
         if commit_status.status == StatusState::Failed && commit_status.description.is_none() {
@@ -347,7 +388,7 @@ impl TestKind {
         let info = TestInfo::from_commit_status(commit_status)?;
 
         match commit_status.status {
-            StatusState::Pending => unreachable!(),
+            StatusState::Pending => Self::new_bisecting(info),
             StatusState::Running => Self::new_running(info),
             StatusState::Success => Self::new_success(info),
             StatusState::Failed => Self::new_failed(info),
@@ -355,9 +396,10 @@ impl TestKind {
         }
     }
 
-    fn as_str(&self) -> &'static str {
+    pub fn as_str(&self) -> &'static str {
         match *self {
             TestKind::Pending => "pending",
+            TestKind::Bisecting { .. } => "bisecting",
             TestKind::Running { .. } => "running",
             TestKind::Success { .. } => "success",
             TestKind::Failed { .. } => "failed",
@@ -365,9 +407,24 @@ impl TestKind {
         }
     }
 
+    fn to_target_url_and_description(&self) -> (Option<&str>, Option<&str>) {
+        match *self {
+            TestKind::Pending |
+            TestKind::Failed(None) => (None, None),
+            TestKind::Bisecting { ref desc, ref info } |
+            TestKind::Running { ref desc, ref info } |
+            TestKind::Success { ref desc, ref info } |
+            TestKind::Failed(Some((ref desc, ref info))) |
+            TestKind::Canceled { ref desc, ref info } => {
+                (Some(info.build_url.as_str()), Some(desc.as_str()))
+            }
+        }
+    }
+
     fn to_status_state(&self) -> StatusState {
         match *self {
-            TestKind::Pending => StatusState::Pending,
+            TestKind::Pending |
+            TestKind::Bisecting { .. } => StatusState::Pending,
             TestKind::Running { .. } => StatusState::Running,
             TestKind::Success { .. } => StatusState::Success,
             TestKind::Failed { .. } => StatusState::Failed,
@@ -387,6 +444,15 @@ pub struct TestInfo {
     pub target_project_id: ProjectId,
     pub target_branch: String,
     pub target_sha: ObjectId,
+    /// Merge requests tested together in a rollup (empty if tested alone)
+    #[serde(default)]
+    pub batch: Vec<MergeRequestId>,
+    /// Reason why the remote rejected the push of the tested commit
+    #[serde(default)]
+    pub push_error: Option<String>,
+    /// The tested commit has been pushed to the target branch
+    #[serde(default)]
+    pub landed: bool,
 }
 
 impl TestInfo {
@@ -402,23 +468,56 @@ impl TestInfo {
     }
 }
 
+/// Commit status context of a test build
+pub trait TestContext {
+    fn status_name() -> &'static str;
+}
+
+/// Test of the merge commit pushed to the merge branch
+#[derive(Debug, Copy, Clone)]
+pub struct AutoTest;
+
+impl TestContext for AutoTest {
+    fn status_name() -> &'static str {
+        "jaba:test"
+    }
+}
+
+/// Unapproved test build requested by `try` command
+#[derive(Debug, Copy, Clone)]
+pub struct TryTest;
+
+impl TestContext for TryTest {
+    fn status_name() -> &'static str {
+        "jaba:try"
+    }
+}
+
 #[derive(Debug)]
-pub struct Test {
+pub struct Build<C> {
     project_id: ProjectId,
     refname: String,
     sha: ObjectId,
     kind: TestKind,
+    context: PhantomData<C>,
 }
 
-impl State for Test {
+pub type Test = Build<AutoTest>;
+/// `TestKind::Pending` means no try build.
+pub type Try = Build<TryTest>;
+
+impl<C> State for Build<C>
+    where C: TestContext
+{
     type Kind = TestKind;
 
     fn init_state(project_id: ProjectId, refname: String, sha: ObjectId) -> Self {
-        Test {
+        Build {
             project_id: project_id,
             refname: refname,
             sha: sha,
             kind: TestKind::Pending,
+            context: PhantomData,
         }
     }
 
@@ -431,16 +530,17 @@ impl State for Test {
             bail!("refname not found")
         };
 
-        Ok(Test {
+        Ok(Build {
             project_id: project_id,
             refname: refname,
             sha: commit_status.sha.clone(),
             kind: kind,
+            context: PhantomData,
         })
     }
 
     fn status_name() -> &'static str {
-        "jaba:test"
+        C::status_name()
     }
 
     fn kind(&self) -> &Self::Kind {
@@ -460,16 +560,7 @@ impl State for Test {
     }
 
     fn to_commit_status_info(&self) -> CommitStatusInfo {
-        let (target_url, description) = match self.kind {
-            TestKind::Pending |
-            TestKind::Failed(None) => (None, None),
-            TestKind::Running { ref desc, ref info } |
-            TestKind::Success { ref desc, ref info } |
-            TestKind::Failed(Some((ref desc, ref info))) |
-            TestKind::Canceled { ref desc, ref info } => {
-                (Some(info.build_url.as_str()), Some(desc.as_str()))
-            }
-        };
+        let (target_url, description) = self.kind.to_target_url_and_description();
 
         CommitStatusInfo {
             refname: Some(&self.refname),
@@ -480,16 +571,9 @@ impl State for Test {
     }
 }
 
-impl Test {
+impl<C> Build<C> {
     pub fn info(&self) -> Option<&TestInfo> {
-        match self.kind {
-            TestKind::Pending |
-            TestKind::Failed(None) => None,
-            TestKind::Running { ref info, .. } |
-            TestKind::Success { ref info, .. } |
-            TestKind::Failed(Some((_, ref info))) |
-            TestKind::Canceled { ref info, .. } => Some(info),
-        }
+        self.kind.info()
     }
 
     pub fn update_kind(&mut self, kind: TestKind) {
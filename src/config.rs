@@ -1,18 +1,92 @@
 pub use errors::*;
+use gitlab::AccessLevel;
 use serde::Deserialize;
-use std::{error, fmt};
+use rustc_serialize::hex::FromHex;
+use std::{env, error, fmt};
 use std::collections::HashMap;
 use std::fs::File;
 use std::io::prelude::*;
 use std::path::{Path, PathBuf};
+use std::time::Duration;
+use template::Template;
 use toml;
 
 const DEFAULT_GIT_CACHE_DIRECTORY: &'static str = "cache";
+const DEFAULT_KNOWN_HOSTS: &'static str = ".ssh/known_hosts";
+const DEFAULT_STORE_FILE: &'static str = "jaba.sqlite3";
+const DEFAULT_AUDIT_FILE: &'static str = "audit.jsonl";
+const DEFAULT_DAEMON_INTERVAL: u64 = 60;
+const DEFAULT_MEMBER_REFRESH_INTERVAL: u64 = 600;
+const DEFAULT_ROLLUP_MAX: usize = 8;
+
+/// Placeholders available in message templates
+pub const MESSAGE_KEYS: &'static [&'static str] = &["author",
+                                                     "sha",
+                                                     "target_branch",
+                                                     "approver",
+                                                     "queue_position",
+                                                     "merge_sha",
+                                                     "build_url",
+                                                     "user",
+                                                     "iid",
+                                                     "url",
+                                                     "source_branch",
+                                                     "error",
+                                                     "request"];
+
+/// Placeholders available in commit message templates
+pub const COMMIT_MESSAGE_KEYS: &'static [&'static str] = &["id",
+                                                            "iid",
+                                                            "title",
+                                                            "description",
+                                                            "source_namespace",
+                                                            "source_branch",
+                                                            "target_branch",
+                                                            "approver",
+                                                            "author",
+                                                            "url"];
+
+const DEFAULT_MERGE_COMMIT_MESSAGE: &'static str = "Auto merge of !{iid} - \
+                                                    {source_namespace}:{source_branch}, \
+                                                    r={approver}\n\n{title}\n\n{description}\n\n\
+                                                    See merge request !{iid}";
+const DEFAULT_TRY_COMMIT_MESSAGE: &'static str = "Try merge of !{iid} - \
+                                                  {source_namespace}:{source_branch}\n\n\
+                                                  {title}\n\n{description}\n\n\
+                                                  See merge request !{iid}";
+const DEFAULT_SQUASH_COMMIT_MESSAGE: &'static str = "{title}\n\n{description}\n\n\
+                                                     See merge request !{iid}, r={approver}";
+
+const DEFAULT_MESSAGE_APPROVED: &'static str =
+    ":pushpin: Commit {sha} has been approved by @{approver}. Queue position: {queue_position}";
+const DEFAULT_MESSAGE_TEST_STARTED: &'static str =
+    ":hourglass: Testing commit {sha} with merge {merge_sha}: {build_url}";
+const DEFAULT_MESSAGE_CONFLICTED: &'static str =
+    ":lock: Merge conflict. Please rebase {sha} onto `{target_branch}`.";
+const DEFAULT_MESSAGE_TEST_FAILED: &'static str = ":broken_heart: Test failed: {build_url}";
+const DEFAULT_MESSAGE_MERGED: &'static str =
+    ":sunny: Test successful. Merged into `{target_branch}` as {merge_sha}.";
+const DEFAULT_MESSAGE_UNAUTHORIZED: &'static str =
+    ":key: @{user}: you are not allowed to approve this merge request.";
+const DEFAULT_MESSAGE_PUSH_REJECTED: &'static str =
+    ":no_entry: Failed to push {merge_sha} to `{target_branch}`: {error}. Comment `retry` once \
+     fixed.";
+const DEFAULT_MESSAGE_CLOSED: &'static str =
+    ":white_check_mark: Landed in `{target_branch}` as {merge_sha}. Closing this merge request.";
+const DEFAULT_MESSAGE_SOURCE_BRANCH_NOT_REMOVED: &'static str =
+    ":warning: Failed to remove the source branch `{source_branch}`: {error}";
+const DEFAULT_MESSAGE_CANNOT_RETRY: &'static str =
+    ":warning: Cannot {request}: merge request has conflicts. Please rebase {sha} onto \
+     `{target_branch}`.";
 
 #[derive(Debug, Clone)]
 pub struct Config {
     pub gitlab: Gitlab,
     pub git: Git,
+    pub daemon: Daemon,
+    pub server: Option<Server>,
+    pub store: Option<Store>,
+    pub audit: Option<Audit>,
     pub repo: HashMap<String, Repo>,
 }
 
@@ -25,13 +99,144 @@ pub struct Gitlab {
 
 #[derive(Debug, Clone)]
 pub struct Git {
-    pub ssh_key: PathBuf,
+    pub auth: GitAuth,
     pub cache_directory: PathBuf,
+    pub host_key_check: HostKeyCheck,
+}
+
+/// How git operations are authenticated
+#[derive(Debug, Clone)]
+pub enum GitAuth {
+    /// SSH private key, optionally encrypted with the passphrase stored in `passphrase_file`
+    SshKey {
+        path: PathBuf,
+        passphrase_file: Option<PathBuf>,
+    },
+    /// Keys held by ssh-agent
+    SshAgent,
+    /// HTTPS with the GitLab access token
+    HttpsToken { token: String },
+}
+
+impl GitAuth {
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            GitAuth::SshKey { .. } => "ssh_key",
+            GitAuth::SshAgent => "ssh_agent",
+            GitAuth::HttpsToken { .. } => "https_token",
+        }
+    }
+
+    pub fn is_https(&self) -> bool {
+        matches!(*self, GitAuth::HttpsToken { .. })
+    }
+}
+
+/// How SSH host keys of remotes are verified
+#[derive(Debug, Clone)]
+pub enum HostKeyCheck {
+    /// Host keys are not verified (`insecure_skip_host_key_check`)
+    Insecure,
+    /// Host keys must match one of the pinned fingerprints or the keys in the known_hosts file
+    Verify {
+        known_hosts: Option<PathBuf>,
+        fingerprints: Vec<HostKeyFingerprint>,
+    },
+}
+
+impl HostKeyCheck {
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            HostKeyCheck::Insecure => "insecure",
+            HostKeyCheck::Verify { .. } => "verify",
+        }
+    }
+}
+
+/// Fingerprint of a host key (`MD5:<hex>` or `SHA1:<hex>`, colons between hex digits allowed)
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum HostKeyFingerprint {
+    Md5(Vec<u8>),
+    Sha1(Vec<u8>),
+}
+
+#[derive(Debug, Clone)]
+pub struct Daemon {
+    pub interval: Duration,
+    /// Interval of reloading project members and reviewer group members
+    pub member_refresh_interval: Duration,
+}
+
+#[derive(Debug, Clone)]
+pub struct Server {
+    pub listen: String,
+    pub webhook_token: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Store {
+    pub path: PathBuf,
+}
+
+#[derive(Debug, Clone)]
+pub struct Audit {
+    pub path: PathBuf,
 }
 
 #[derive(Debug, Clone)]
 pub struct Repo {
     pub name: String,
+    pub rollup_max: usize,
+    pub merge_strategy: MergeStrategy,
+    /// Removes source branches after merged even if not requested in the merge requests
+    pub remove_source_branch: bool,
+    pub reviewer_min_access_level: Option<AccessLevel>,
+    pub reviewers: Vec<String>,
+    pub reviewer_groups: Vec<String>,
+    pub commit_messages: CommitMessages,
+    pub messages: Messages,
+}
+
+/// How merge requests are applied onto the target branch
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum MergeStrategy {
+    /// Merge commit with the target branch and the source branch as parents
+    Merge,
+    /// Source commits replayed onto the target branch
+    Rebase,
+    /// Single commit authored by the merge request author
+    Squash,
+    /// Source branch itself, which must be based on the target branch
+    FastForwardOnly,
+}
+
+/// Templates of the commit messages created by jaba
+#[derive(Debug, Clone)]
+pub struct CommitMessages {
+    pub merge: Template,
+    pub try_merge: Template,
+    pub squash: Template,
+    /// Headings of the merge request description sections removed from the messages
+    pub strip_sections: Vec<String>,
+    /// Removes task list items (`- [ ] ...`) from the merge request description
+    pub strip_task_lists: bool,
+}
+
+/// Templates of the merge request notes posted on state changes. Empty messages are not posted.
+#[derive(Debug, Clone)]
+pub struct Messages {
+    pub approved: Template,
+    pub test_started: Template,
+    pub conflicted: Template,
+    pub test_failed: Template,
+    pub merged: Template,
+    pub unauthorized: Template,
+    /// Posted when pushing the merged commit is rejected other than by non-fast-forward. The test
+    /// is failed (not errored) with the reason until `retry` is requested
+    pub push_rejected: Template,
+    pub closed: Template,
+    pub source_branch_not_removed: Template,
+    pub cannot_retry: Template,
 }
 
 pub fn from_path<P>(path: P) -> Result<Config>
@@ -53,8 +258,23 @@ pub fn from_path<P>(path: P) -> Result<Config>
 
     // Converts relative path into absolute path
     let basedir = path.parent().expect("invalid config file path");
-    config.git.ssh_key = basedir.join(config.git.ssh_key);
+    if let GitAuth::SshKey { ref mut path, ref mut passphrase_file } = config.git.auth {
+        *path = basedir.join(&path);
+        if let Some(ref mut passphrase_file) = *passphrase_file {
+            *passphrase_file = basedir.join(&passphrase_file);
+        }
+    }
+    if let HostKeyCheck::Verify { known_hosts: Some(ref mut known_hosts), .. } =
+        config.git.host_key_check {
+        *known_hosts = basedir.join(&known_hosts);
+    }
     config.git.cache_directory = basedir.join(config.git.cache_directory);
+    if let Some(ref mut store) = config.store {
+        store.path = basedir.join(&store.path);
+    }
+    if let Some(ref mut audit) = config.audit {
+        audit.path = basedir.join(&audit.path);
+    }
 
     Ok(config)
 }
@@ -79,23 +299,54 @@ fn parse_toml(input: &str) -> Result<toml::Value> {
 
 fn decode(toml: toml::Value) -> Result<Config> {
     let raw: RawConfig = Deserialize::deserialize(&mut toml::Decoder::new(toml))?;
-    Ok(raw.into())
+    raw.into_config()
 }
 
 #[derive(Deserialize)]
 struct RawConfig {
     gitlab: RawGitlab,
     git: RawGit,
+    daemon: Option<RawDaemon>,
+    server: Option<RawServer>,
+    store: Option<RawStore>,
+    audit: Option<RawAudit>,
+    messages: Option<RawMessages>,
     repo: HashMap<String, RawRepo>,
 }
 
-impl Into<Config> for RawConfig {
-    fn into(self) -> Config {
-        Config {
-            gitlab: self.gitlab.into(),
-            git: self.git.into(),
-            repo: self.repo.into_iter().map(|(name, repo)| (name, repo.into())).collect(),
+impl RawConfig {
+    fn into_config(self) -> Result<Config> {
+        let messages = self.messages.unwrap_or_default();
+        let mut repo = HashMap::new();
+        for (label, raw) in self.repo {
+            let r = raw.into_repo(&messages)
+                .chain_err(|| format!("invalid repository config: {}", label))?;
+            let _ = repo.insert(label, r);
         }
+
+        let git = self.git.into_git(&self.gitlab)?;
+        let store = self.store.map(|store| {
+            Store {
+                path: store.path
+                    .unwrap_or_else(|| git.cache_directory.join(DEFAULT_STORE_FILE)),
+            }
+        });
+        let audit = self.audit.map(|audit| {
+            Audit {
+                path: audit.path
+                    .unwrap_or_else(|| git.cache_directory.join(DEFAULT_AUDIT_FILE)),
+            }
+        });
+
+        Ok(Config {
+            gitlab: self.gitlab.into(),
+            git: git,
+            daemon: self.daemon.unwrap_or_default().into(),
+            server: self.server.map(Into::into),
+            store: store,
+            audit: audit,
+            repo: repo,
+        })
     }
 }
 
@@ -118,30 +369,277 @@ impl Into<Gitlab> for RawGitlab {
 
 #[derive(Deserialize)]
 struct RawGit {
-    ssh_key: PathBuf,
+    auth: Option<String>,
+    ssh_key: Option<PathBuf>,
+    ssh_key_passphrase_file: Option<PathBuf>,
     cache_directory: Option<PathBuf>,
+    known_hosts: Option<PathBuf>,
+    host_key_fingerprints: Option<Vec<String>>,
+    insecure_skip_host_key_check: Option<bool>,
 }
 
-impl Into<Git> for RawGit {
-    fn into(self) -> Git {
-        Git {
-            ssh_key: self.ssh_key,
+impl RawGit {
+    fn into_git(self, gitlab: &RawGitlab) -> Result<Git> {
+        let auth = match self.auth.as_ref().map(|s| s.as_str()).unwrap_or("ssh_key") {
+            "ssh_key" => {
+                let path = match self.ssh_key {
+                    Some(path) => path,
+                    None => bail!("git.ssh_key is required for ssh_key authentication"),
+                };
+                GitAuth::SshKey {
+                    path: path,
+                    passphrase_file: self.ssh_key_passphrase_file,
+                }
+            }
+            "ssh_agent" => GitAuth::SshAgent,
+            "https_token" => GitAuth::HttpsToken { token: gitlab.access_token.clone() },
+            auth => bail!("invalid git authentication: {}", auth),
+        };
+
+        let host_key_check = if self.insecure_skip_host_key_check.unwrap_or(false) {
+            HostKeyCheck::Insecure
+        } else {
+            let mut fingerprints = vec![];
+            for fingerprint in self.host_key_fingerprints.unwrap_or_default() {
+                fingerprints.push(parse_host_key_fingerprint(&fingerprint)?);
+            }
+            // Falls back to the user's known_hosts as ssh does
+            let known_hosts = match self.known_hosts {
+                Some(known_hosts) => Some(known_hosts),
+                None if fingerprints.is_empty() => {
+                    env::home_dir().map(|home| home.join(DEFAULT_KNOWN_HOSTS))
+                }
+                None => None,
+            };
+            HostKeyCheck::Verify {
+                known_hosts: known_hosts,
+                fingerprints: fingerprints,
+            }
+        };
+
+        Ok(Git {
+            auth: auth,
             cache_directory: self.cache_directory.unwrap_or(DEFAULT_GIT_CACHE_DIRECTORY.into()),
+            host_key_check: host_key_check,
+        })
+    }
+}
+
+#[derive(Default, Deserialize)]
+struct RawDaemon {
+    interval: Option<u64>,
+    member_refresh_interval: Option<u64>,
+}
+
+impl Into<Daemon> for RawDaemon {
+    fn into(self) -> Daemon {
+        Daemon {
+            interval: Duration::from_secs(self.interval.unwrap_or(DEFAULT_DAEMON_INTERVAL)),
+            member_refresh_interval: Duration::from_secs(self.member_refresh_interval
+                .unwrap_or(DEFAULT_MEMBER_REFRESH_INTERVAL)),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct RawServer {
+    listen: String,
+    webhook_token: Option<String>,
+}
+
+impl Into<Server> for RawServer {
+    fn into(self) -> Server {
+        Server {
+            listen: self.listen,
+            webhook_token: self.webhook_token,
         }
     }
 }
 
+#[derive(Deserialize)]
+struct RawStore {
+    path: Option<PathBuf>,
+}
+
+#[derive(Deserialize)]
+struct RawAudit {
+    path: Option<PathBuf>,
+}
+
 #[derive(Deserialize)]
 struct RawRepo {
     name: String,
+    rollup_max: Option<usize>,
+    merge_strategy: Option<String>,
+    remove_source_branch: Option<bool>,
+    reviewer_min_access_level: Option<String>,
+    reviewers: Option<Vec<String>>,
+    reviewer_groups: Option<Vec<String>>,
+    merge_commit_message: Option<String>,
+    try_commit_message: Option<String>,
+    squash_commit_message: Option<String>,
+    strip_description_sections: Option<Vec<String>>,
+    strip_task_lists: Option<bool>,
+    messages: Option<RawMessages>,
+}
+
+impl RawRepo {
+    fn into_repo(self, messages: &RawMessages) -> Result<Repo> {
+        let reviewers = self.reviewers.unwrap_or_default();
+        let reviewer_groups = self.reviewer_groups.unwrap_or_default();
+
+        // Project members are not reviewers by default if reviewers are listed explicitly
+        let reviewer_min_access_level = match self.reviewer_min_access_level {
+            Some(level) => Some(parse_access_level(&level)?),
+            None if reviewers.is_empty() && reviewer_groups.is_empty() => {
+                Some(AccessLevel::Master)
+            }
+            None => None,
+        };
+
+        Ok(Repo {
+            name: self.name,
+            rollup_max: self.rollup_max.unwrap_or(DEFAULT_ROLLUP_MAX),
+            merge_strategy: match self.merge_strategy {
+                Some(strategy) => parse_merge_strategy(&strategy)?,
+                None => MergeStrategy::Merge,
+            },
+            remove_source_branch: self.remove_source_branch.unwrap_or(false),
+            reviewer_min_access_level: reviewer_min_access_level,
+            reviewers: reviewers,
+            reviewer_groups: reviewer_groups,
+            commit_messages: CommitMessages {
+                merge: parse_commit_message("merge_commit_message",
+                                            self.merge_commit_message,
+                                            DEFAULT_MERGE_COMMIT_MESSAGE)?,
+                try_merge: parse_commit_message("try_commit_message",
+                                                self.try_commit_message,
+                                                DEFAULT_TRY_COMMIT_MESSAGE)?,
+                squash: parse_commit_message("squash_commit_message",
+                                             self.squash_commit_message,
+                                             DEFAULT_SQUASH_COMMIT_MESSAGE)?,
+                strip_sections: self.strip_description_sections.unwrap_or_default(),
+                strip_task_lists: self.strip_task_lists.unwrap_or(false),
+            },
+            messages: self.messages.unwrap_or_default().or(messages).into_messages()?,
+        })
+    }
+}
+
+#[derive(Clone, Default, Deserialize)]
+struct RawMessages {
+    approved: Option<String>,
+    test_started: Option<String>,
+    conflicted: Option<String>,
+    test_failed: Option<String>,
+    merged: Option<String>,
+    unauthorized: Option<String>,
+    push_rejected: Option<String>,
+    closed: Option<String>,
+    source_branch_not_removed: Option<String>,
+    cannot_retry: Option<String>,
 }
 
-impl Into<Repo> for RawRepo {
-    fn into(self) -> Repo {
-        Repo { name: self.name }
+impl RawMessages {
+    // Messages not set in the repository config are taken from the global config
+    fn or(self, base: &RawMessages) -> RawMessages {
+        let base = base.clone();
+        RawMessages {
+            approved: self.approved.or(base.approved),
+            test_started: self.test_started.or(base.test_started),
+            conflicted: self.conflicted.or(base.conflicted),
+            test_failed: self.test_failed.or(base.test_failed),
+            merged: self.merged.or(base.merged),
+            unauthorized: self.unauthorized.or(base.unauthorized),
+            push_rejected: self.push_rejected.or(base.push_rejected),
+            closed: self.closed.or(base.closed),
+            source_branch_not_removed: self.source_branch_not_removed
+                .or(base.source_branch_not_removed),
+            cannot_retry: self.cannot_retry.or(base.cannot_retry),
+        }
+    }
+
+    fn into_messages(self) -> Result<Messages> {
+        Ok(Messages {
+            approved: parse_message("approved", self.approved, DEFAULT_MESSAGE_APPROVED)?,
+            test_started: parse_message("test_started",
+                                        self.test_started,
+                                        DEFAULT_MESSAGE_TEST_STARTED)?,
+            conflicted: parse_message("conflicted", self.conflicted, DEFAULT_MESSAGE_CONFLICTED)?,
+            test_failed: parse_message("test_failed",
+                                       self.test_failed,
+                                       DEFAULT_MESSAGE_TEST_FAILED)?,
+            merged: parse_message("merged", self.merged, DEFAULT_MESSAGE_MERGED)?,
+            unauthorized: parse_message("unauthorized",
+                                        self.unauthorized,
+                                        DEFAULT_MESSAGE_UNAUTHORIZED)?,
+            push_rejected: parse_message("push_rejected",
+                                         self.push_rejected,
+                                         DEFAULT_MESSAGE_PUSH_REJECTED)?,
+            closed: parse_message("closed", self.closed, DEFAULT_MESSAGE_CLOSED)?,
+            source_branch_not_removed:
+                parse_message("source_branch_not_removed",
+                              self.source_branch_not_removed,
+                              DEFAULT_MESSAGE_SOURCE_BRANCH_NOT_REMOVED)?,
+            cannot_retry: parse_message("cannot_retry",
+                                        self.cannot_retry,
+                                        DEFAULT_MESSAGE_CANNOT_RETRY)?,
+        })
     }
 }
 
+fn parse_message(name: &str, message: Option<String>, default: &str) -> Result<Template> {
+    let message = message.as_ref().map(|s| s.as_str()).unwrap_or(default);
+    Template::parse(message, MESSAGE_KEYS).chain_err(|| format!("invalid message: {}", name))
+}
+
+fn parse_commit_message(name: &str, message: Option<String>, default: &str) -> Result<Template> {
+    let message = message.as_ref().map(|s| s.as_str()).unwrap_or(default);
+    Template::parse(message, COMMIT_MESSAGE_KEYS).chain_err(|| format!("invalid {}", name))
+}
+
+fn parse_host_key_fingerprint(fingerprint: &str) -> Result<HostKeyFingerprint> {
+    let mut parts = fingerprint.splitn(2, ':');
+    let (kind, hex) = match (parts.next(), parts.next()) {
+        (Some(kind), Some(hex)) => (kind, hex.replace(':', "")),
+        _ => bail!("invalid host key fingerprint: {}", fingerprint),
+    };
+    let hash = match hex.from_hex() {
+        Ok(hash) => hash,
+        Err(_) => bail!("invalid host key fingerprint: {}", fingerprint),
+    };
+
+    let fingerprint = match kind.to_uppercase().as_str() {
+        "MD5" if hash.len() == 16 => HostKeyFingerprint::Md5(hash),
+        "SHA1" if hash.len() == 20 => HostKeyFingerprint::Sha1(hash),
+        _ => bail!("invalid host key fingerprint: {}", fingerprint),
+    };
+    Ok(fingerprint)
+}
+
+fn parse_merge_strategy(strategy: &str) -> Result<MergeStrategy> {
+    let strategy = match strategy {
+        "merge" => MergeStrategy::Merge,
+        "rebase" => MergeStrategy::Rebase,
+        "squash" => MergeStrategy::Squash,
+        "ff-only" => MergeStrategy::FastForwardOnly,
+        _ => bail!("invalid merge strategy: {}", strategy),
+    };
+    Ok(strategy)
+}
+
+fn parse_access_level(level: &str) -> Result<AccessLevel> {
+    let level = match level {
+        "guest" => AccessLevel::Guest,
+        "reporter" => AccessLevel::Reporter,
+        "developer" => AccessLevel::Developer,
+        "master" => AccessLevel::Master,
+        "owner" => AccessLevel::Owner,
+        _ => bail!("invalid access level: {}", level),
+    };
+    Ok(level)
+}
+
 #[derive(Debug)]
 pub struct TomlParserError {
     lo_pos: (usize, usize),
@@ -183,3 +681,37 @@ impl error::Error for TomlParserError {
         self.raw.cause()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // `ssh-keygen -l -E md5` and `-E sha1` (in hex) of an ed25519 host key
+    const MD5: &'static str = "2f:30:7a:6e:2e:0f:f6:1f:6b:fe:34:5d:bd:64:57:10";
+    const SHA1: &'static str = "af:96:30:5d:8b:ab:d0:a4:a7:93:93:3d:31:a0:7a:1b:ce:bd:86:a4";
+
+    #[test]
+    fn parse_host_key_fingerprints() {
+        assert_eq!(parse_host_key_fingerprint(&format!("MD5:{}", MD5)).unwrap(),
+                   HostKeyFingerprint::Md5(vec![0x2f, 0x30, 0x7a, 0x6e, 0x2e, 0x0f, 0xf6, 0x1f,
+                                                0x6b, 0xfe, 0x34, 0x5d, 0xbd, 0x64, 0x57, 0x10]));
+        assert_eq!(parse_host_key_fingerprint(&format!("SHA1:{}", SHA1)).unwrap(),
+                   HostKeyFingerprint::Sha1(vec![0xaf, 0x96, 0x30, 0x5d, 0x8b, 0xab, 0xd0, 0xa4,
+                                                 0xa7, 0x93, 0x93, 0x3d, 0x31, 0xa0, 0x7a, 0x1b,
+                                                 0xce, 0xbd, 0x86, 0xa4]));
+        assert_eq!(parse_host_key_fingerprint(&format!("md5:{}", MD5.replace(':', ""))).unwrap(),
+                   parse_host_key_fingerprint(&format!("MD5:{}", MD5)).unwrap());
+    }
+
+    #[test]
+    fn parse_invalid_host_key_fingerprints() {
+        // Lengths must match the hash
+        assert!(parse_host_key_fingerprint(&format!("MD5:{}", SHA1)).is_err());
+        assert!(parse_host_key_fingerprint(&format!("SHA1:{}", MD5)).is_err());
+        assert!(parse_host_key_fingerprint(&format!("SHA256:{}", SHA1)).is_err());
+        assert!(parse_host_key_fingerprint(MD5).is_err());
+        // Base64 fingerprints printed by recent OpenSSH are not supported
+        assert!(parse_host_key_fingerprint("SHA1:r5YwXYur0KSnk5M9MaB6G869hqQ").is_err());
+        assert!(parse_host_key_fingerprint("MD5:2g307a6e2e0ff61f6bfe345dbd645710").is_err());
+    }
+}
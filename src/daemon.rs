@@ -0,0 +1,154 @@
+use chan::{self, Receiver};
+use chan_signal::Signal;
+use config::Config;
+use errors::*;
+use gitlab_ext::GitlabExt;
+use metrics::Metrics;
+use project::Project;
+use server;
+use slog::Logger;
+use status::StatusBoard;
+use std::collections::HashMap;
+use std::time::Instant;
+use webhook::Trigger;
+
+pub fn serve(log: &Logger,
+             config: &Config,
+             gitlab: &GitlabExt,
+             signal: Receiver<Signal>)
+             -> Result<()> {
+    let log = log.new(o!("scope" => "daemon"));
+    info!(log, "start daemon"; "interval" => config.daemon.interval.as_secs());
+
+    // The sender is kept alive even without server so that `hook.recv()` never returns
+    let (hook_tx, hook) = chan::async();
+    let status = StatusBoard::new();
+    let metrics = Metrics::new();
+    let mut listening = match config.server {
+        Some(ref server_config) => {
+            Some(server::start(&log,
+                               server_config,
+                               hook_tx.clone(),
+                               status.clone(),
+                               metrics.clone())?)
+        }
+        None => None,
+    };
+
+    let tick = chan::tick(config.daemon.interval);
+    let mut projects = HashMap::new();
+    let mut trigger = None;
+    let mut members_refreshed_at = Instant::now();
+
+    loop {
+        // Reviewers added or removed in GitLab are taken into account without restart
+        if members_refreshed_at.elapsed() >= config.daemon.member_refresh_interval {
+            for (label, project) in &mut projects {
+                if let Err(e) = project.refresh_members() {
+                    warn!(log, "failed to refresh members"; "repository" => label.as_str());
+                    super::dump_error(&log, &e);
+                }
+            }
+            members_refreshed_at = Instant::now();
+        }
+
+        for (label, repo) in &config.repo {
+            if projects.contains_key(label) {
+                continue;
+            }
+
+            // Projects failed to open are retried on the next evaluation
+            match Project::new(&log,
+                               label,
+                               repo,
+                               &config.git,
+                               gitlab,
+                               &metrics,
+                               config.store.as_ref(),
+                               config.audit.as_ref()) {
+                Ok(project) => {
+                    project.preflight();
+                    let _ = projects.insert(label, project);
+                }
+                Err(e) => {
+                    warn!(log, "failed to open project"; "repository" => label.as_str());
+                    super::dump_error(&log, &e);
+                }
+            }
+        }
+
+        // Pipelines and pushes of forks affect the projects having merge requests from them
+        let source_project_id = match trigger {
+            Some(ref trigger) if trigger.from_source &&
+                                 !config.repo.values().any(|repo| repo.name == trigger.project) => {
+                match gitlab.gitlab().project_by_name(&trigger.project) {
+                    Ok(source_project) => Some(source_project.id),
+                    Err(e) => {
+                        warn!(log, "failed to get source project";
+                              "project" => trigger.project.as_str());
+                        super::dump_error(&log, &Error::from(e));
+                        None
+                    }
+                }
+            }
+            _ => None,
+        };
+
+        for (label, project) in &projects {
+            let target_branch = match trigger {
+                None => None,
+                Some(ref trigger) if trigger.project == config.repo[*label].name => {
+                    trigger.target_branch.as_ref()
+                }
+                Some(_) => {
+                    let is_affected = source_project_id.map_or(Ok(false), |id| {
+                        project.has_merge_request_from(id)
+                    });
+                    match is_affected {
+                        Ok(true) => None,
+                        Ok(false) => continue,
+                        Err(e) => {
+                            warn!(log, "failed to get merge requests";
+                                  "repository" => label.as_str());
+                            super::dump_error(&log, &e);
+                            continue;
+                        }
+                    }
+                }
+            };
+
+            match super::run_project(project, target_branch.map(|s| s.as_str())) {
+                Ok(queues) => {
+                    status.update(project.label(), target_branch.map(|s| s.as_str()), queues)
+                }
+                Err(e) => {
+                    warn!(log, "failed to running on repository";
+                          "repository" => label.as_str());
+                    super::dump_error(&log, &e);
+                }
+            }
+        }
+
+        // In-flight operations are never interrupted; signals are handled between evaluations
+        chan_select! {
+            signal.recv() -> signal => {
+                info!(log, "signal received. shutting down";
+                      "signal" => format!("{:?}", signal));
+                break;
+            },
+            tick.recv() => {
+                trigger = None;
+            },
+            hook.recv() -> hook_trigger => {
+                trigger = hook_trigger;
+            },
+        }
+    }
+
+    if let Some(ref mut listening) = listening {
+        let _ = listening.close();
+    }
+    drop(hook_tx);
+
+    Ok(())
+}
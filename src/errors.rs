@@ -1,19 +1,43 @@
 use config;
 use git2;
 use gitlab;
+use hyper;
 use log;
+use project::PushRejection;
+use reqwest;
+use rusqlite;
 use serde_json;
 use std::io;
 use toml;
+use url;
 
 error_chain! {
     foreign_links {
         Git(git2::Error);
         Gitlab(gitlab::Error);
+        Hyper(hyper::Error);
         TomlDecode(toml::DecodeError);
         TomlParser(config::TomlParserError);
         SetLogger(log::SetLoggerError);
         SerdeJson(serde_json::Error);
         Io(io::Error);
+        Reqwest(reqwest::Error);
+        UrlParse(url::ParseError);
+        Sqlite(rusqlite::Error);
+    }
+
+    errors {
+        GitlabApi(status: String, body: String) {
+            description("GitLab API request failed")
+            display("GitLab API request failed: {}: {}", status, body)
+        }
+        HostKeyVerification(hostname: String) {
+            description("SSH host key verification failed")
+            display("SSH host key verification failed: {}", hostname)
+        }
+        PushRejected(refname: String, reason: PushRejection, message: String) {
+            description("push rejected by the remote")
+            display("push rejected ({}): {}: {}", reason.as_str(), refname, message)
+        }
     }
 }
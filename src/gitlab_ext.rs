@@ -1,13 +1,75 @@
+use chrono::{DateTime, UTC};
 use config::Gitlab as GitlabConfig;
 use errors::*;
-use gitlab::{Gitlab, UserFull};
+use gitlab::{Gitlab, GroupId, Member, MergeRequestId, PipelineId, ProjectId, UserBasic, UserFull};
+use reqwest::{Client, Method};
+use reqwest::header::Headers;
+use serde::Deserialize;
+use serde_json::{self, Value};
 use slog::Logger;
+use std::io::prelude::*;
+use url::Url;
+use url::percent_encoding::{PATH_SEGMENT_ENCODE_SET, utf8_percent_encode};
+
+const PER_PAGE: usize = 100;
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct MergeRequestNote {
+    pub id: u64,
+    pub body: String,
+    pub author: UserBasic,
+    pub created_at: DateTime<UTC>,
+    pub system: bool,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct MergeRequestVersion {
+    pub id: u64,
+    pub head_commit_sha: String,
+    pub created_at: DateTime<UTC>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct GroupInfo {
+    pub id: u64,
+    pub full_path: Option<String>,
+    pub parent_id: Option<u64>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct RepositoryBranch {
+    pub name: String,
+    pub protected: bool,
+    pub commit: RepositoryBranchCommit,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct RepositoryBranchCommit {
+    pub id: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ProtectedBranch {
+    /// Branch name or wildcard pattern (e.g. `auto-*`)
+    pub name: String,
+    #[serde(default)]
+    pub push_access_levels: Vec<ProtectedBranchAccessLevel>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ProtectedBranchAccessLevel {
+    /// Minimum access level allowed to push (0 means no one)
+    pub access_level: u64,
+}
 
 #[derive(Debug)]
 pub struct GitlabExt {
     log: Logger,
     gitlab: Gitlab,
     current_user: UserFull,
+    client: Client,
+    base_url: String,
+    access_token: String,
 }
 
 impl GitlabExt {
@@ -26,10 +88,15 @@ impl GitlabExt {
                "user" => current_user.name,
                "email" => current_user.email);
 
+        let scheme = if conf.insecure { "http" } else { "https" };
+
         Ok(GitlabExt {
             log: log,
             gitlab: gitlab,
             current_user: current_user,
+            client: Client::new()?,
+            base_url: format!("{}://{}/api/v3/", scheme, conf.host),
+            access_token: conf.access_token.clone(),
         })
     }
 
@@ -40,4 +107,142 @@ impl GitlabExt {
     pub fn current_user(&self) -> &UserFull {
         &self.current_user
     }
+
+    pub fn retry_pipeline(&self, project: ProjectId, pipeline: PipelineId) -> Result<()> {
+        let _: Value = self.api(Method::Post,
+                                &format!("projects/{}/pipelines/{}/retry", project, pipeline),
+                                &[])?;
+        Ok(())
+    }
+
+    pub fn cancel_pipeline(&self, project: ProjectId, pipeline: PipelineId) -> Result<()> {
+        let _: Value = self.api(Method::Post,
+                                &format!("projects/{}/pipelines/{}/cancel", project, pipeline),
+                                &[])?;
+        Ok(())
+    }
+
+    pub fn merge_request_notes(&self,
+                               project: ProjectId,
+                               merge_request: MergeRequestId)
+                               -> Result<Vec<MergeRequestNote>> {
+        let mut notes: Vec<MergeRequestNote> =
+            self.api_all(&format!("projects/{}/merge_requests/{}/notes", project, merge_request))?;
+        notes.sort_by_key(|note| note.id);
+        Ok(notes)
+    }
+
+    pub fn create_merge_request_note(&self,
+                                     project: ProjectId,
+                                     merge_request: MergeRequestId,
+                                     body: &str)
+                                     -> Result<MergeRequestNote> {
+        self.api(Method::Post,
+                 &format!("projects/{}/merge_requests/{}/notes", project, merge_request),
+                 &[("body", body)])
+    }
+
+    pub fn close_merge_request(&self,
+                               project: ProjectId,
+                               merge_request: MergeRequestId)
+                               -> Result<()> {
+        let _: Value = self.api(Method::Put,
+                                &format!("projects/{}/merge_requests/{}", project, merge_request),
+                                &[("state_event", "close")])?;
+        Ok(())
+    }
+
+    pub fn merge_request_versions(&self,
+                                  project: ProjectId,
+                                  merge_request: MergeRequestId)
+                                  -> Result<Vec<MergeRequestVersion>> {
+        self.api_all(&format!("projects/{}/merge_requests/{}/versions", project, merge_request))
+    }
+
+    pub fn protected_branches(&self, project: ProjectId) -> Result<Vec<ProtectedBranch>> {
+        self.api_all(&format!("projects/{}/protected_branches", project))
+    }
+
+    pub fn repository_branch(&self, project: ProjectId, branch: &str) -> Result<RepositoryBranch> {
+        let branch = utf8_percent_encode(branch, PATH_SEGMENT_ENCODE_SET).to_string();
+        self.api(Method::Get,
+                 &format!("projects/{}/repository/branches/{}", project, branch),
+                 &[])
+    }
+
+    pub fn delete_branch(&self, project: ProjectId, branch: &str) -> Result<()> {
+        let branch = utf8_percent_encode(branch, PATH_SEGMENT_ENCODE_SET).to_string();
+        let _: Value = self.api(Method::Delete,
+                                &format!("projects/{}/repository/branches/{}", project, branch),
+                                &[])?;
+        Ok(())
+    }
+
+    pub fn group_by_path(&self, path: &str) -> Result<GroupInfo> {
+        let path = utf8_percent_encode(path, PATH_SEGMENT_ENCODE_SET).to_string();
+        self.api(Method::Get, &format!("groups/{}", path), &[])
+    }
+
+    /// Returns members of the group including members inherited from its parent groups.
+    pub fn inherited_group_members(&self, group: GroupId) -> Result<Vec<Member>> {
+        let mut members = vec![];
+        let mut group_id = Some(group.value());
+        while let Some(id) = group_id {
+            members.extend(self.gitlab.group_members(GroupId::new(id))?);
+            let group: GroupInfo = self.api(Method::Get, &format!("groups/{}", id), &[])?;
+            group_id = group.parent_id;
+        }
+        Ok(members)
+    }
+
+    fn api_all<T>(&self, path: &str) -> Result<Vec<T>>
+        where T: Deserialize
+    {
+        let per_page = PER_PAGE.to_string();
+        let mut all = vec![];
+        for page in 1.. {
+            let page = page.to_string();
+            let items: Vec<T> =
+                self.api(Method::Get, path, &[("page", &page), ("per_page", &per_page)])?;
+            let is_last = items.len() < PER_PAGE;
+            all.extend(items);
+            if is_last {
+                break;
+            }
+        }
+        Ok(all)
+    }
+
+    // Endpoints not provided by `gitlab` crate are called through this method
+    fn api<T>(&self, method: Method, path: &str, params: &[(&str, &str)]) -> Result<T>
+        where T: Deserialize
+    {
+        let url = Url::parse(&self.base_url)?.join(path)?;
+        trace!(self.log, "api request"; "method" => method.to_string(), "url" => url.to_string());
+
+        let mut headers = Headers::new();
+        headers.set_raw("PRIVATE-TOKEN", vec![self.access_token.clone().into_bytes()]);
+
+        let req = match method {
+            Method::Get | Method::Delete => {
+                let mut url = url;
+                let _ = url.query_pairs_mut().extend_pairs(params);
+                self.client.request(method, url)
+            }
+            _ => self.client.request(method, url).form(&params),
+        };
+
+        let mut res = req.headers(headers).send()?;
+        let mut body = String::new();
+        let _ = res.read_to_string(&mut body)?;
+
+        if !res.status().is_success() {
+            bail!(ErrorKind::GitlabApi(res.status().to_string(), body));
+        }
+
+        if body.is_empty() {
+            body = "null".into();
+        }
+        Ok(serde_json::from_str(&body)?)
+    }
 }
@@ -0,0 +1,271 @@
+use config::{HostKeyCheck, HostKeyFingerprint};
+use errors::*;
+use git2::Cert;
+use rustc_serialize::base64::FromBase64;
+use sha1::Sha1;
+use std::ascii::AsciiExt;
+use std::fs::File;
+use std::io::BufReader;
+use std::io::prelude::*;
+use std::path::Path;
+
+const SHA1_BLOCK_SIZE: usize = 64;
+const HASHED_HOST_PREFIX: &'static str = "|1|";
+pub const DEFAULT_SSH_PORT: u16 = 22;
+
+/// Verifies the SSH host key of the remote against the pinned fingerprints or the known_hosts
+/// file.
+pub fn verify(check: &HostKeyCheck, cert: &Cert, hostname: &str, port: u16) -> Result<()> {
+    let (known_hosts, fingerprints) = match *check {
+        HostKeyCheck::Insecure => return Ok(()),
+        HostKeyCheck::Verify { ref known_hosts, ref fingerprints } => (known_hosts, fingerprints),
+    };
+
+    let hostkey = match cert.as_hostkey() {
+        Some(hostkey) => hostkey,
+        None => bail!("not an SSH host key"),
+    };
+    let md5 = hostkey.hash_md5().map(|hash| &hash[..]);
+    let sha1 = hostkey.hash_sha1().map(|hash| &hash[..]);
+
+    let is_pinned = fingerprints.iter().any(|fingerprint| {
+        match *fingerprint {
+            HostKeyFingerprint::Md5(ref hash) => md5 == Some(&hash[..]),
+            HostKeyFingerprint::Sha1(ref hash) => sha1 == Some(&hash[..]),
+        }
+    });
+    if is_pinned {
+        return Ok(());
+    }
+
+    let known_hosts = match *known_hosts {
+        Some(ref known_hosts) => known_hosts,
+        None => bail!("host key is not pinned"),
+    };
+    let sha1 = match sha1 {
+        Some(sha1) => sha1,
+        None => bail!("SHA-1 hash of the host key is not available"),
+    };
+
+    let mut is_known = false;
+    for key in known_host_keys(known_hosts, &host_key_name(hostname, port))? {
+        match key {
+            KnownHostKey::Revoked(ref key) if sha1_digest(key)[..] == *sha1 => {
+                bail!("host key is revoked in {}", known_hosts.to_string_lossy())
+            }
+            KnownHostKey::Trusted(ref key) if sha1_digest(key)[..] == *sha1 => is_known = true,
+            _ => {}
+        }
+    }
+    if !is_known {
+        bail!("host key does not match any key in {}", known_hosts.to_string_lossy());
+    }
+
+    Ok(())
+}
+
+enum KnownHostKey {
+    Trusted(Vec<u8>),
+    Revoked(Vec<u8>),
+}
+
+/// Returns the SSH port of the remote URL (`ssh://[user@]host[:port]/path` or
+/// `[user@]host:path`).
+pub fn ssh_port(url: &str) -> u16 {
+    let authority = match url.find("://") {
+        Some(pos) => url[pos + 3..].split('/').next().unwrap_or_default(),
+        None => return DEFAULT_SSH_PORT,
+    };
+    let host = authority.rsplitn(2, '@').next().unwrap_or_default();
+    let port = match host.rfind(':') {
+        Some(pos) if !host[pos..].contains(']') => &host[pos + 1..],
+        _ => return DEFAULT_SSH_PORT,
+    };
+    port.parse().unwrap_or(DEFAULT_SSH_PORT)
+}
+
+// Host names are looked up as `[host]:port` for non-default ports, as OpenSSH does
+fn host_key_name(hostname: &str, port: u16) -> String {
+    if port == DEFAULT_SSH_PORT {
+        hostname.to_string()
+    } else {
+        format!("[{}]:{}", hostname, port)
+    }
+}
+
+/// Reads the keys of the host from the known_hosts file (see sshd(8)). `hostname` is the name
+/// returned from `host_key_name`.
+fn known_host_keys(path: &Path, hostname: &str) -> Result<Vec<KnownHostKey>> {
+    let file = File::open(path)
+        .chain_err(|| format!("failed to open known_hosts: {}", path.to_string_lossy()))?;
+
+    let mut keys = vec![];
+    for line in BufReader::new(file).lines() {
+        let line = line?;
+        let mut fields = line.split_whitespace();
+
+        let (is_revoked, hosts) = match fields.next() {
+            None => continue,
+            Some(field) if field.starts_with('#') => continue,
+            // Certificate authorities are not supported
+            Some("@cert-authority") => continue,
+            Some("@revoked") => (true, fields.next()),
+            Some(field) => (false, Some(field)),
+        };
+        let key = match (hosts, fields.next(), fields.next()) {
+            (Some(hosts), Some(_key_type), Some(key)) if matches_hosts(hosts, hostname) => key,
+            _ => continue,
+        };
+        let key = match key.from_base64() {
+            Ok(key) => key,
+            Err(_) => continue,
+        };
+
+        keys.push(if is_revoked {
+            KnownHostKey::Revoked(key)
+        } else {
+            KnownHostKey::Trusted(key)
+        });
+    }
+
+    Ok(keys)
+}
+
+fn matches_hosts(hosts: &str, hostname: &str) -> bool {
+    if hosts.starts_with(HASHED_HOST_PREFIX) {
+        return matches_hashed_host(&hosts[HASHED_HOST_PREFIX.len()..], hostname);
+    }
+
+    let mut is_matched = false;
+    for pattern in hosts.split(',') {
+        let (is_negated, pattern) = if pattern.starts_with('!') {
+            (true, &pattern[1..])
+        } else {
+            (false, pattern)
+        };
+
+        if matches_pattern(pattern.as_bytes(), hostname.as_bytes()) {
+            if is_negated {
+                return false;
+            }
+            is_matched = true;
+        }
+    }
+    is_matched
+}
+
+// `|1|<base64 salt>|<base64 HMAC-SHA1 of hostname>`
+fn matches_hashed_host(hashed: &str, hostname: &str) -> bool {
+    let mut parts = hashed.splitn(2, '|');
+    let (salt, hash) = match (parts.next(), parts.next()) {
+        (Some(salt), Some(hash)) => (salt.from_base64(), hash.from_base64()),
+        _ => return false,
+    };
+    match (salt, hash) {
+        (Ok(salt), Ok(hash)) => hmac_sha1(&salt, hostname.as_bytes())[..] == hash[..],
+        _ => false,
+    }
+}
+
+// Glob with `*` and `?` (case-insensitive as host names)
+fn matches_pattern(pattern: &[u8], name: &[u8]) -> bool {
+    match (pattern.first(), name.first()) {
+        (None, None) => true,
+        (Some(&b'*'), _) => {
+            matches_pattern(&pattern[1..], name) ||
+            (!name.is_empty() && matches_pattern(pattern, &name[1..]))
+        }
+        (Some(&b'?'), Some(_)) => matches_pattern(&pattern[1..], &name[1..]),
+        (Some(p), Some(n)) if p.eq_ignore_ascii_case(n) => {
+            matches_pattern(&pattern[1..], &name[1..])
+        }
+        _ => false,
+    }
+}
+
+fn sha1_digest(data: &[u8]) -> [u8; 20] {
+    let mut sha1 = Sha1::new();
+    sha1.update(data);
+    sha1.digest().bytes()
+}
+
+fn hmac_sha1(key: &[u8], message: &[u8]) -> [u8; 20] {
+    let mut block = [0; SHA1_BLOCK_SIZE];
+    if key.len() > SHA1_BLOCK_SIZE {
+        block[..20].copy_from_slice(&sha1_digest(key));
+    } else {
+        block[..key.len()].copy_from_slice(key);
+    }
+
+    let mut inner = Sha1::new();
+    inner.update(&block.iter().map(|b| b ^ 0x36).collect::<Vec<_>>());
+    inner.update(message);
+
+    let mut outer = Sha1::new();
+    outer.update(&block.iter().map(|b| b ^ 0x5c).collect::<Vec<_>>());
+    outer.update(&inner.digest().bytes());
+    outer.digest().bytes()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Hashed by `ssh-keygen -H` from `gitlab.example.com` and `[gitlab.example.com]:2222`
+    const HASHED_HOST: &'static str =
+        "|1|XTE8d1mkUwvYYpL6tAdXt7rLv0g=|CEXPICfKJJbUzIXQF0afRpDNzT8=";
+    const HASHED_HOST_2222: &'static str =
+        "|1|w7JAdVkDwm9jQrOsyh8BSl3zzo0=|rGmFPPdTDn5CGXY7JNES58/21Ns=";
+
+    fn to_hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    #[test]
+    fn hmac_sha1_rfc2202() {
+        assert_eq!(to_hex(&hmac_sha1(&[0x0b; 20], b"Hi There")),
+                   "b617318655057264e28bc0b6fb378c8ef146be00");
+        assert_eq!(to_hex(&hmac_sha1(b"Jefe", b"what do ya want for nothing?")),
+                   "effcdf6ae5eb2fa2d27416d5f184df9c259a7c79");
+        assert_eq!(to_hex(&hmac_sha1(&[0xaa; 80],
+                                     b"Test Using Larger Than Block-Size Key - Hash Key First")),
+                   "aa4ae5e15272d00e95705637ce8a3b55ed402112");
+    }
+
+    #[test]
+    fn matches_hashed_hosts() {
+        assert!(matches_hosts(HASHED_HOST, &host_key_name("gitlab.example.com", 22)));
+        assert!(!matches_hosts(HASHED_HOST, &host_key_name("gitlab.example.com", 2222)));
+        assert!(!matches_hosts(HASHED_HOST, &host_key_name("gitlab.example.org", 22)));
+        assert!(matches_hosts(HASHED_HOST_2222, &host_key_name("gitlab.example.com", 2222)));
+        assert!(!matches_hosts(HASHED_HOST_2222, &host_key_name("gitlab.example.com", 22)));
+        assert!(!matches_hosts("|1|invalid|hash", "gitlab.example.com"));
+    }
+
+    #[test]
+    fn matches_plain_hosts() {
+        let name = host_key_name("gitlab.example.com", 22);
+        assert!(matches_hosts("gitlab.example.com", &name));
+        assert!(matches_hosts("GitLab.Example.com,10.0.0.1", &name));
+        assert!(matches_hosts("*.example.com", &name));
+        assert!(matches_hosts("gitlab.example.co?", &name));
+        assert!(!matches_hosts("*.example.com,!gitlab.example.com", &name));
+        assert!(!matches_hosts("[gitlab.example.com]:2222", &name));
+
+        let name = host_key_name("gitlab.example.com", 2222);
+        assert!(matches_hosts("[gitlab.example.com]:2222", &name));
+        assert!(matches_hosts("[*.example.com]:2222", &name));
+        assert!(!matches_hosts("[gitlab.example.com]:2022", &name));
+        assert!(!matches_hosts("gitlab.example.com", &name));
+    }
+
+    #[test]
+    fn ssh_ports() {
+        assert_eq!(ssh_port("git@gitlab.example.com:group/project.git"), 22);
+        assert_eq!(ssh_port("ssh://git@gitlab.example.com/group/project.git"), 22);
+        assert_eq!(ssh_port("ssh://git@gitlab.example.com:2222/group/project.git"), 2222);
+        assert_eq!(ssh_port("ssh://gitlab.example.com:2222/project.git"), 2222);
+        assert_eq!(ssh_port("ssh://git@[::1]:2222/project.git"), 2222);
+        assert_eq!(ssh_port("ssh://git@[::1]/project.git"), 22);
+    }
+}
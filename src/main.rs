@@ -26,19 +26,27 @@
 //!
 //! ![State transition diagram](_img/state_transition.png)
 
+#[macro_use]
+extern crate chan;
+extern crate chan_signal;
 extern crate chrono;
 extern crate clap;
 #[macro_use]
 extern crate error_chain;
 extern crate git2;
 extern crate gitlab;
+extern crate hyper;
 extern crate log;
 #[macro_use]
 extern crate matches;
+extern crate reqwest;
+extern crate rusqlite;
+extern crate rustc_serialize;
 #[macro_use]
 extern crate serde_derive;
 extern crate serde_json;
 extern crate serde;
+extern crate sha1;
 #[macro_use]
 extern crate slog;
 #[macro_use]
@@ -46,36 +54,63 @@ extern crate slog_envlogger;
 extern crate slog_stdlog;
 extern crate slog_term;
 extern crate toml;
+extern crate url;
 
-use build_state::ApprovalInfo as ApprovalStateInfo;
-use config::{Git as GitConfig, Repo as RepoConfig};
+use build_state::{ApprovalInfo as ApprovalStateInfo, Rollup};
+use chrono::{DateTime, NaiveDate, TimeZone, UTC};
+use chan_signal::Signal;
+use config::{Config, Repo as RepoConfig};
 use errors::*;
 use gitlab_ext::GitlabExt;
 use log::LogLevelFilter;
 use merge_request::{MergeRequest, State as MergeRequestState};
+use metrics::Metrics;
 use project::{BranchInfo, Project};
 use slog::{DrainExt, Level, LevelFilter, Logger};
+use status::{MergeRequestStatus, QueueStatus};
 use std::cmp::Ordering;
 use std::collections::{BinaryHeap, HashMap};
 use std::collections::hash_map::Entry;
+use std::mem;
 use std::path::PathBuf;
 
+mod audit;
 mod build_state;
 mod config;
+mod daemon;
 mod errors;
 mod gitlab_ext;
+mod host_key;
 mod merge_request;
+mod metrics;
 mod project;
+mod server;
+mod status;
+mod store;
+mod template;
+mod webhook;
 
 const APP_NAME: &'static str = env!("CARGO_PKG_NAME");
 const APP_VERSION: &'static str = env!("CARGO_PKG_VERSION");
 
 const DEFAULT_CONFIG_PATH: &'static str = "etc/cfg.toml";
 
+#[derive(Debug, Clone, Eq, PartialEq)]
+enum Command {
+    Run,
+    Serve,
+    Check,
+    Audit {
+        repo: Option<String>,
+        since: Option<String>,
+    },
+}
+
 #[derive(Debug)]
 struct Arg {
     config_path: PathBuf,
     log_level: u64,
+    command: Command,
 }
 
 fn parse_arg() -> Arg {
@@ -89,11 +124,38 @@ fn parse_arg() -> Arg {
             .value_name("FILE")
             .help("Sets a custom config file path"))
         .arg(clap::Arg::with_name("v").short("v").multiple(true).help("Sets a level of verbosity"))
+        .subcommand(clap::SubCommand::with_name("serve")
+            .about("Runs as a daemon, evaluating queues periodically"))
+        .subcommand(clap::SubCommand::with_name("check")
+            .about("Checks whether the bot user can push to the branches of each repository"))
+        .subcommand(clap::SubCommand::with_name("audit")
+            .about("Prints audit log records in the JSON Lines format")
+            .arg(clap::Arg::with_name("repo")
+                .long("repo")
+                .value_name("REPO")
+                .help("Prints records of the repository (label or project path) only"))
+            .arg(clap::Arg::with_name("since")
+                .long("since")
+                .value_name("DATE")
+                .help("Prints records since the date (YYYY-MM-DD or RFC 3339)")))
         .get_matches();
 
+    let command = match matches.subcommand() {
+        ("serve", _) => Command::Serve,
+        ("check", _) => Command::Check,
+        ("audit", Some(matches)) => {
+            Command::Audit {
+                repo: matches.value_of("repo").map(Into::into),
+                since: matches.value_of("since").map(Into::into),
+            }
+        }
+        _ => Command::Run,
+    };
+
     Arg {
         config_path: matches.value_of("config").unwrap_or(DEFAULT_CONFIG_PATH).into(),
         log_level: matches.occurrences_of("v"),
+        command: command,
     }
 }
 
@@ -188,9 +250,61 @@ impl<'a> Queue<'a> {
             MergeRequestState::Errored => self.errored.push(mr),
         }
     }
+
+    fn push_errored(&mut self, mut mr: MergeRequest<'a>) {
+        mr.mark_errored();
+        self.errored.push(mr);
+    }
+
+    fn to_status(&self, project: &Project, target_branch_name: &str) -> QueueStatus {
+        let status = |mrs: Vec<&MergeRequest>| -> Vec<MergeRequestStatus> {
+            mrs.into_iter().map(MergeRequestStatus::new).collect()
+        };
+        let sorted = |heap: &BinaryHeap<SortBy<ApprovalStateInfo, MergeRequest<'a>>>| {
+            let mut mrs = heap.iter().collect::<Vec<_>>();
+            mrs.sort_by(|a, b| b.cmp(a));
+            status(mrs.into_iter().map(|&SortBy(_, ref mr)| mr).collect())
+        };
+
+        QueueStatus {
+            repo: project.label().into(),
+            project: project.repo_config().name.clone(),
+            target_branch: target_branch_name.into(),
+            updated_at: UTC::now(),
+            errored: status(self.errored.iter().collect()),
+            init: status(self.init.iter().collect()),
+            approved: sorted(&self.approved),
+            running: sorted(&self.running),
+            success: sorted(&self.success),
+            merged: status(self.merged.iter().collect()),
+            failed: status(self.failed.iter().map(|&(_, ref mr)| mr).collect()),
+        }
+    }
+
+    fn record_metrics(&self, project: &Project, target_branch_name: &str) {
+        let metrics = project.metrics();
+        let label = project.label();
+        for &(bucket, size) in &[("errored", self.errored.len()),
+                                 ("init", self.init.len()),
+                                 ("approved", self.approved.len()),
+                                 ("running", self.running.len()),
+                                 ("success", self.success.len()),
+                                 ("merged", self.merged.len()),
+                                 ("failed", self.failed.len())] {
+            metrics.set_queue_size(label, target_branch_name, bucket, size);
+        }
+    }
+
+    fn notify_approved(&mut self) {
+        let approved = mem::replace(&mut self.approved, BinaryHeap::new()).into_sorted_vec();
+        for (i, SortBy(approval, mut mr)) in approved.into_iter().rev().enumerate() {
+            mr.notify_approved(i + 1);
+            self.approved.push(SortBy(approval, mr));
+        }
+    }
 }
 
-fn run_repo_target(log: &Logger, queue: &mut Queue) -> Result<()> {
+fn run_repo_target(log: &Logger, repo_config: &RepoConfig, queue: &mut Queue) -> Result<()> {
     info!(log, "# of queue";
               "errored" => queue.errored.len(),
               "init" => queue.init.len(),
@@ -207,16 +321,35 @@ fn run_repo_target(log: &Logger, queue: &mut Queue) -> Result<()> {
             Err(e) => {
                 warn!(mr.log(), "failed to push merged");
                 dump_error(mr.log(), &e);
-                queue.errored.push(mr);
+                queue.push_errored(mr);
                 continue;
             }
             Ok(is_pushed) => is_pushed,
         };
 
-        queue.push(mr);
         if is_pushed {
+            // Other merge requests in the same rollup have been landed together
+            let merge_sha = mr.test_info().map(|info| info.merge_sha.clone());
+            queue.push(mr);
+
+            let success = mem::replace(&mut queue.success, BinaryHeap::new());
+            for SortBy(approval, mut mr) in success {
+                if mr.test_info().map(|info| &info.merge_sha) != merge_sha.as_ref() {
+                    queue.success.push(SortBy(approval, mr));
+                    continue;
+                }
+                if let Err(e) = mr.mark_merged() {
+                    warn!(mr.log(), "failed to mark as merged");
+                    dump_error(mr.log(), &e);
+                    queue.push_errored(mr);
+                    continue;
+                }
+                queue.push(mr);
+            }
+
             return Ok(());
         } else {
+            queue.push(mr);
             continue;
         }
     }
@@ -231,20 +364,63 @@ fn run_repo_target(log: &Logger, queue: &mut Queue) -> Result<()> {
         return Ok(());
     }
 
-    while let Some(SortBy(_approval, mut mr)) = queue.approved.pop() {
+    while let Some(SortBy(approval, mr)) = queue.approved.pop() {
         info!(mr.log(), "approved mr"; "mr" => *mr.state());
 
-        let is_started = match mr.start_test(&queue.target_branch) {
+        let mut batch = vec![];
+        let mut rest = vec![];
+        if let Some(info) = mr.bisecting_info().cloned() {
+            // Test the first half of the remaining merge requests in the failed rollup
+            let mut group = vec![mr];
+            while let Some(SortBy(_approval, mr)) = queue.approved.pop() {
+                if mr.bisecting_info().map(|i| &i.merge_sha) == Some(&info.merge_sha) {
+                    group.push(mr);
+                } else {
+                    rest.push(mr);
+                }
+            }
+            group.sort_by_key(|mr| info.batch.iter().position(|id| *id == mr.merge_request().id));
+
+            let half = (group.len() + 1) / 2;
+            info!(log, "bisecting failed rollup";
+                  "sha" => *info.merge_sha.value(),
+                  "testing" => half,
+                  "remaining" => group.len() - half);
+            rest.extend(group.drain(half..));
+            batch = group;
+        } else if approval.rollup == Rollup::Always {
+            batch.push(mr);
+            while let Some(SortBy(approval, mr)) = queue.approved.pop() {
+                if batch.len() < repo_config.rollup_max && approval.rollup != Rollup::Never &&
+                   mr.bisecting_info().is_none() {
+                    info!(mr.log(), "rolled up"; "mr" => *mr.state());
+                    batch.push(mr);
+                } else {
+                    rest.push(mr);
+                }
+            }
+        } else {
+            batch.push(mr);
+        }
+        for mr in rest {
+            queue.push(mr);
+        }
+
+        let is_started = match merge_request::start_test(&mut batch, &queue.target_branch) {
             Err(e) => {
-                warn!(mr.log(), "failed to start test");
-                dump_error(mr.log(), &e);
-                queue.errored.push(mr);
+                warn!(log, "failed to start test");
+                dump_error(log, &e);
+                for mr in batch {
+                    queue.push_errored(mr);
+                }
                 continue;
             }
             Ok(is_started) => is_started,
         };
 
-        queue.push(mr);
+        for mr in batch {
+            queue.push(mr);
+        }
         if is_started {
             return Ok(());
         } else {
@@ -255,23 +431,37 @@ fn run_repo_target(log: &Logger, queue: &mut Queue) -> Result<()> {
     Ok(())
 }
 
-fn run_repo(log: &Logger,
-            label: &str,
-            repo_config: &RepoConfig,
-            gitlab: &GitlabExt,
-            git_config: &GitConfig)
-            -> Result<()> {
-    let project = Project::new(log, label, repo_config, git_config, gitlab)?;
+fn run_repo(log: &Logger, label: &str, config: &Config, gitlab: &GitlabExt) -> Result<()> {
+    let project = Project::new(log,
+                               label,
+                               &config.repo[label],
+                               &config.git,
+                               gitlab,
+                               &Metrics::new(),
+                               config.store.as_ref(),
+                               config.audit.as_ref())?;
+    project.preflight();
+    let _ = run_project(&project, None)?;
+    Ok(())
+}
 
+/// Evaluates the queues of the project and returns their snapshots.
+fn run_project(project: &Project, target_branch: Option<&str>) -> Result<Vec<QueueStatus>> {
     let mut map = HashMap::new();
     for mut mr in project.opened_merge_requests()? {
+        if let Some(target_branch) = target_branch {
+            if mr.merge_request().target_branch != target_branch {
+                continue;
+            }
+        }
+
         let mut queue = {
             let target_branch_name = &mr.merge_request().target_branch;
 
             match map.entry(target_branch_name.clone()) {
                 Entry::Occupied(e) => e.into_mut(),
                 Entry::Vacant(e) => {
-                    let queue = Queue::new(&project, target_branch_name)?;
+                    let queue = Queue::new(project, target_branch_name)?;
                     e.insert(queue)
                 }
             }
@@ -280,39 +470,70 @@ fn run_repo(log: &Logger,
         if let Err(e) = mr.update_target_branch(&queue.target_branch) {
             warn!(mr.log(), "failed to update target branch info");
             dump_error(mr.log(), &e);
-            queue.errored.push(mr);
+            queue.push_errored(mr);
             continue;
         }
 
+        // Try builds are independent of the queue
+        if mr.is_try_requested() {
+            if let Err(e) = mr.start_try(&queue.target_branch) {
+                warn!(mr.log(), "failed to start try");
+                dump_error(mr.log(), &e);
+            }
+        }
+
         queue.push(mr);
     }
 
+    project.metrics().reset_queue_sizes(project.label(), target_branch);
+
+    let mut statuses = vec![];
     for (target_branch_name, queue) in &mut map {
-        let log = log.new(o!("target_branch" => target_branch_name.to_string()));
-        if let Err(e) = run_repo_target(&log, queue) {
+        let log = project.log().new(o!("target_branch" => target_branch_name.to_string()));
+        queue.record_metrics(project, target_branch_name);
+        queue.notify_approved();
+        if let Err(e) = run_repo_target(&log, project.repo_config(), queue) {
             warn!(project.log(), "failed to handle target branch";
                   "taget_branch" => *target_branch_name);
             dump_error(&log, &e);
         };
+        statuses.push(queue.to_status(project, target_branch_name));
     }
 
-    Ok(())
+    Ok(statuses)
 }
 
-fn run(log: Logger, arg: Arg) -> Result<()> {
+fn run(log: Logger, arg: Arg, signal: Option<chan::Receiver<Signal>>) -> Result<()> {
     info!(log, "start"; "package" => APP_NAME, "version" => APP_VERSION);
 
     let config = config::from_path(arg.config_path)?;
     debug!(log, "configuration file loaded";
            "gitlab.host" => config.gitlab.host,
            "gitlab.insecure" => config.gitlab.insecure,
-           "git.ssh_key" => config.git.ssh_key.to_string_lossy().to_string(),
-           "git.cache_directory" => config.git.cache_directory.to_string_lossy().to_string());
+           "git.auth" => config.git.auth.as_str(),
+           "git.host_key_check" => config.git.host_key_check.as_str(),
+           "git.cache_directory" => config.git.cache_directory.to_string_lossy().to_string(),
+           "daemon.interval" => config.daemon.interval.as_secs(),
+           "daemon.member_refresh_interval" => config.daemon.member_refresh_interval.as_secs());
+
+    if let Command::Audit { ref repo, ref since } = arg.command {
+        return run_audit(&config,
+                         repo.as_ref().map(String::as_str),
+                         since.as_ref().map(String::as_str));
+    }
 
     let gitlab = GitlabExt::new(&log, &config.gitlab)?;
 
-    for (label, repo) in &config.repo {
-        if let Err(e) = run_repo(&log, label, repo, &gitlab, &config.git) {
+    if arg.command == Command::Check {
+        return run_check(&config, &gitlab);
+    }
+
+    if let Some(signal) = signal {
+        return daemon::serve(&log, &config, &gitlab, signal);
+    }
+
+    for label in config.repo.keys() {
+        if let Err(e) = run_repo(&log, label, &config, &gitlab) {
             warn!(log, "failed to running on repository";
                   "repository" => label.as_str());
             dump_error(&log, &e);
@@ -322,11 +543,74 @@ fn run(log: Logger, arg: Arg) -> Result<()> {
     Ok(())
 }
 
+fn run_check(config: &Config, gitlab: &GitlabExt) -> Result<()> {
+    let mut labels = config.repo.keys().collect::<Vec<_>>();
+    labels.sort();
+
+    let mut is_ok = true;
+    for label in labels {
+        match project::check_permissions(gitlab, &config.repo[label]) {
+            Ok(ref problems) if problems.is_empty() => println!("{}: ok", label),
+            Ok(problems) => {
+                is_ok = false;
+                for problem in problems {
+                    println!("{}: {}: {}", label, problem.branch, problem.message);
+                }
+            }
+            Err(e) => {
+                is_ok = false;
+                println!("{}: error: {}", label, e);
+            }
+        }
+    }
+
+    if !is_ok {
+        bail!("permission problems found");
+    }
+    Ok(())
+}
+
+fn run_audit(config: &Config, repo: Option<&str>, since: Option<&str>) -> Result<()> {
+    let audit_config = if let Some(ref audit_config) = config.audit {
+        audit_config
+    } else {
+        bail!("audit log is not enabled")
+    };
+
+    let since = match since {
+        Some(since) => Some(parse_date(since)?),
+        None => None,
+    };
+
+    for record in audit::query(&audit_config.path, repo, since)? {
+        println!("{}", serde_json::to_string(&record)?);
+    }
+
+    Ok(())
+}
+
+fn parse_date(s: &str) -> Result<DateTime<UTC>> {
+    if let Ok(date) = NaiveDate::parse_from_str(s, "%Y-%m-%d") {
+        return Ok(UTC.from_utc_datetime(&date.and_hms(0, 0, 0)));
+    }
+    let time = DateTime::parse_from_rfc3339(s).chain_err(|| format!("invalid date: {}", s))?;
+    Ok(time.with_timezone(&UTC))
+}
+
 fn main() {
     let arg = parse_arg();
+
+    // Signal mask must be set before any thread (including the logger thread) is spawned
+    let signal = match arg.command {
+        Command::Run |
+        Command::Check |
+        Command::Audit { .. } => None,
+        Command::Serve => Some(chan_signal::notify(&[Signal::INT, Signal::TERM])),
+    };
+
     let log = create_logger(arg.log_level);
 
-    if let Err(e) = run(log, arg) {
+    if let Err(e) = run(log, arg, signal) {
         println!("error: {}", e);
         for e in e.iter().skip(1) {
             println!("caused by: {}", e);
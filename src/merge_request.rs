@@ -1,19 +1,30 @@
+use audit::{self, Record as AuditRecord};
 use build_state::{Approval as ApprovalState, ApprovalInfo as ApprovalStateInfo,
-                  ApprovalKind as ApprovalStateKind, State as BuildState, Test as TestState,
-                  TestInfo as TestStateInfo, TestKind as TestStateKind};
+                  ApprovalKind as ApprovalStateKind, Rollup, State as BuildState,
+                  Test as TestState, TestInfo as TestStateInfo, TestKind as TestStateKind,
+                  Try as TryState};
+use chrono::{DateTime, UTC};
 use errors::*;
-use git2::{STATUS_CONFLICTED, Signature};
-use git2::build::CheckoutBuilder;
-use gitlab::{self, CommitNote, CommitStatus, MergeStatus, ObjectId, ProjectId, StatusState,
-             UserFull};
-use gitlab_ext::GitlabExt;
-use project::{BranchInfo, Project};
+use config::MergeStrategy;
+use git2::{self, Commit, Oid, Signature};
+use gitlab::{self, CommitStatus, MergeStatus, ObjectId, ProjectId, StatusState,
+             UserBasic, UserFull};
+use gitlab_ext::{GitlabExt, MergeRequestNote};
+use metrics::Counter;
+use project::{BranchInfo, Project, PushRejection};
+use store::Store;
 use slog::{self, Logger};
-use std::cmp::Ordering;
-use std::collections::HashMap;
+use std::cmp::{self, Ordering};
+use std::collections::{HashMap, HashSet};
 use std::collections::hash_map::Entry;
 use std::fmt::Debug;
 
+pub const MERGE_BRANCH_PREFIX: &'static str = "auto-";
+pub const TRY_BRANCH_PREFIX: &'static str = "try-";
+
+const NOTIFICATION_MARKER: &'static str = "<!-- jaba:notify:";
+const NOTIFICATION_MARKER_END: &'static str = " -->";
+
 #[derive(Debug, Clone, Eq, PartialEq)]
 pub enum State {
     Init,
@@ -26,7 +37,7 @@ pub enum State {
 }
 
 impl State {
-    fn as_str(&self) -> &'static str {
+    pub fn as_str(&self) -> &'static str {
         match *self {
             State::Init => "init",
             State::Approved { .. } => "approved",
@@ -37,6 +48,16 @@ impl State {
             State::Errored => "errored",
         }
     }
+    pub fn approval(&self) -> Option<&ApprovalStateInfo> {
+        match *self {
+            State::Init | State::Errored | State::Failed(None) => None,
+            State::Approved(ref approval) |
+            State::Running(ref approval) |
+            State::Success(ref approval) |
+            State::Merged(ref approval) |
+            State::Failed(Some(ref approval)) => Some(approval),
+        }
+    }
 }
 
 impl slog::Serialize for State {
@@ -66,6 +87,44 @@ impl slog::Serialize for State {
     }
 }
 
+/// Merge request notes posted on state changes
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum Notification {
+    Approved,
+    TestStarted,
+    Conflicted,
+    TestFailed,
+    Merged,
+    Unauthorized,
+    PushRejected,
+    Closed,
+    SourceBranchNotRemoved,
+    CannotRetry,
+}
+
+impl Notification {
+    fn as_str(&self) -> &'static str {
+        match *self {
+            Notification::Approved => "approved",
+            Notification::TestStarted => "test_started",
+            Notification::Conflicted => "conflicted",
+            Notification::TestFailed => "test_failed",
+            Notification::Merged => "merged",
+            Notification::Unauthorized => "unauthorized",
+            Notification::PushRejected => "push_rejected",
+            Notification::Closed => "closed",
+            Notification::SourceBranchNotRemoved => "source_branch_not_removed",
+            Notification::CannotRetry => "cannot_retry",
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum MergeKind {
+    Auto,
+    Try,
+}
+
 pub struct MergeRequest<'a> {
     log: Logger,
     project: &'a Project<'a>,
@@ -73,13 +132,17 @@ pub struct MergeRequest<'a> {
     state: State,
     approval_state: ApprovalState,
     test_state: TestState,
+    try_state: TryState,
     merged: bool,
+    test_request: Option<(TestRequest, DateTime<UTC>)>,
+    try_requested: bool,
     pipeline_state: HashMap<String, CommitStatus>,
+    notified: HashSet<String>,
 }
 
 impl<'a> MergeRequest<'a> {
     pub fn from_gitlab_mr(project: &'a Project, mr: gitlab::MergeRequest) -> Self {
-        let log = project.log().new(o!("merge_request" => mr.id.value()));
+        let log = project.log().new(o!("merge_request" => mr.iid.value()));
         debug!(log, "start merge_request";
                "source_project" => mr.source_project_id.value(),
                "source_branch" => mr.source_branch.to_string(),
@@ -102,8 +165,15 @@ impl<'a> MergeRequest<'a> {
             }
         };
 
-        let approval_state: ApprovalState = create_state_from_pipeline(&log, &mr, &pipeline_state);
-        let test_state: TestState = create_state_from_pipeline(&log, &mr, &pipeline_state);
+        let store = project.store();
+        let approval_state: ApprovalState =
+            create_state_from_pipeline(&log, store, &mr, &pipeline_state);
+        let test_state: TestState = create_state_from_pipeline(&log, store, &mr, &pipeline_state);
+        let try_state: TryState = create_state_from_pipeline(&log, store, &mr, &pipeline_state);
+        let merged = match *test_state.kind() {
+            TestStateKind::Success { ref info, .. } => info.landed,
+            _ => false,
+        };
 
         let mut obj = MergeRequest {
             log: log,
@@ -112,8 +182,12 @@ impl<'a> MergeRequest<'a> {
             merge_request: mr,
             test_state: test_state,
             approval_state: approval_state,
-            merged: false,
+            try_state: try_state,
+            merged: merged,
+            test_request: None,
+            try_requested: false,
             pipeline_state: pipeline_state,
+            notified: HashSet::new(),
         };
 
         while result.is_ok() {
@@ -133,6 +207,20 @@ impl<'a> MergeRequest<'a> {
                 break;
             }
 
+            if let Err(e) = obj.update_try_status() {
+                warn!(obj.log, "failed to update try status");
+                super::dump_error(&obj.log, &e);
+                result = Err(());
+                break;
+            }
+
+            if let Err(e) = obj.apply_test_request() {
+                warn!(obj.log, "failed to apply test request");
+                super::dump_error(&obj.log, &e);
+                result = Err(());
+                break;
+            }
+
             if let Err(e) = obj.sync_commit_status() {
                 warn!(obj.log, "failed to sync commit status");
                 super::dump_error(&obj.log, &e);
@@ -144,8 +232,15 @@ impl<'a> MergeRequest<'a> {
         }
 
         if result.is_err() {
-            obj.state = State::Errored;
-        };
+            obj.mark_errored();
+        } else {
+            obj.notify_loaded_conflict();
+            obj.record_state();
+            if obj.merged {
+                // Closing the landed merge request failed last time
+                obj.close_landed();
+            }
+        }
 
         info!(obj.log, "loaded merge request status"; "status" => obj.state);
 
@@ -156,6 +251,14 @@ impl<'a> MergeRequest<'a> {
         &self.log
     }
 
+    /// Marks the merge request as errored. Errored merge requests are evaluated again next time.
+    pub fn mark_errored(&mut self) {
+        if self.state != State::Errored {
+            self.state = State::Errored;
+            self.project.metrics().increment(Counter::Errored, self.project.label());
+        }
+    }
+
     pub fn state(&self) -> &State {
         &self.state
     }
@@ -164,8 +267,15 @@ impl<'a> MergeRequest<'a> {
         &self.merge_request
     }
 
+    /// URL of the merge request page (GitLab links merge requests by the project-local iid).
+    pub fn web_url(&self) -> String {
+        format!("{}/merge_requests/{}",
+                self.project.project().web_url,
+                self.merge_request.iid.value())
+    }
+
     pub fn update_target_branch(&mut self, target_branch: &BranchInfo) -> Result<()> {
-        let info = if let Some(info) = self.test_state.info() {
+        let info = if let Some(info) = self.running_test_info() {
             info.clone()
         } else {
             debug!(self.log, "test status not changed via target branch info";
@@ -175,14 +285,17 @@ impl<'a> MergeRequest<'a> {
 
         let target_sha = target_branch.gitlab_object_id();
 
-        if info.target_sha != target_sha {
-            // TODO: Cancel current running build
+        let is_landed = matches!(*self.test_state.kind(), TestStateKind::Success { .. }) &&
+                        info.merge_sha == target_sha;
+        if info.target_sha != target_sha && !is_landed {
+            self.cancel_test(&info)?;
             let next_kind = TestStateKind::Pending;
             debug!(self.log, "test status changed via target branch info";
                    "before" => *self.test_state.kind(),
                    "after" => next_kind);
             self.test_state.update_kind(next_kind);
             self.trans_state()?;
+            self.sync_commit_status()?;
         } else {
             debug!(self.log, "test status not changed via target branch info";
                    "status" => *self.test_state.kind());
@@ -191,89 +304,41 @@ impl<'a> MergeRequest<'a> {
         Ok(())
     }
 
-    pub fn start_test(&mut self, target_branch: &BranchInfo) -> Result<bool> {
-        assert_matches!(self.state, State::Approved {..});
-        assert_matches!(*self.test_state.kind(), TestStateKind::Pending);
-
-        let source_project =
-            self.project.gitlab().gitlab().project(self.merge_request.source_project_id)?;
-        let repository = self.project.repository();
-
-        // Fetch source branch
-        repository.remote_set_url("mr", &source_project.ssh_url_to_repo)?;
-        let source_branch = self.project
-            .repository_fetch_branch("mr", &self.merge_request.source_branch)?;
-
-        // Avoid force update current HEAD branch error
-        self.project.repository_reset_branch(&target_branch.branch)?;
-
-        // Create merge branch
-        let merge_branch_name = format!("auto-{}", self.merge_request.target_branch);
-        let merge_branch = repository.branch(&merge_branch_name, &target_branch.commit, true)?;
-        let merge_branch_ref = merge_branch.get().name().unwrap();
-
-        self.project.repository_reset_branch(&merge_branch)?;
-
-        // Merge
-        let annotated_commits =
-            &[&repository.reference_to_annotated_commit(source_branch.branch.get())?];
-        let mut cb = CheckoutBuilder::new();
-        let _ = cb.force();
-        repository.merge(annotated_commits, None, Some(&mut cb))?;
-        let conflicted = repository.statuses(None)?
-            .iter()
-            .any(|state| state.status() == STATUS_CONFLICTED);
+    pub fn test_info(&self) -> Option<&TestStateInfo> {
+        self.test_state.info()
+    }
 
-        if conflicted {
-            info!(self.log, "conflicted!");
-            repository.cleanup_state()?;
-            self.test_state.update_kind(TestStateKind::Failed(None));
-            self.trans_state()?;
-            self.sync_commit_status()?;
-            return Ok(false);
+    pub fn bisecting_info(&self) -> Option<&TestStateInfo> {
+        match *self.test_state.kind() {
+            TestStateKind::Bisecting { ref info, .. } => Some(info),
+            _ => None,
         }
+    }
 
-        // Commit
-        let merge_sha = {
-            let update_ref = Some(merge_branch_ref);
-            let sig = self.merge_commit_signature()?;
-            let message = self.merge_commit_message(&source_project);
-            let tree_oid = repository.index()?.write_tree()?;
-            let tree = repository.find_tree(tree_oid)?;
-            let parents = &[&target_branch.commit, &source_branch.commit];
-            let merge_commit_oid =
-                repository.commit(update_ref, &sig, &sig, &message, &tree, parents)?;
-
-            repository.cleanup_state()?;
-
-            merge_commit_oid.to_string()
-        };
-
-        info!(self.log, "successfully merged"; "sha" => merge_sha);
-
-        // Force push
-        let refspec = format!("+{}", merge_branch_ref);
-        self.project.repository_push_branch("origin", &refspec)?;
-        info!(self.log, "successfully pushed");
+    // Test info of the tests already started and not finished by push rejections (i.e. except
+    // bisecting ones)
+    fn running_test_info(&self) -> Option<&TestStateInfo> {
+        if self.bisecting_info().is_some() {
+            return None;
+        }
+        match self.test_state.info() {
+            Some(info) if info.push_error.is_some() => None,
+            info => info,
+        }
+    }
 
-        // Update status
-        let test = TestStateInfo {
-            build_url: format!("{}/commit/{}/builds", self.project.project().web_url, merge_sha),
-            merge_sha: ObjectId::new(&merge_sha),
-            merge_branch: merge_branch_name,
-            source_project_id: self.merge_request.source_project_id,
-            source_branch: self.merge_request.source_branch.clone(),
-            source_sha: source_branch.gitlab_object_id(),
-            target_project_id: self.merge_request.target_project_id,
-            target_branch: self.merge_request.target_branch.clone(),
-            target_sha: target_branch.gitlab_object_id(),
+    /// Acknowledges the approval with the position in the queue (1-origin).
+    pub fn notify_approved(&mut self, queue_position: usize) {
+        let approval = match self.state {
+            State::Approved(ref approval) if self.bisecting_info().is_none() => approval.clone(),
+            _ => return,
         };
 
-        self.test_state.update_kind(TestStateKind::new_running(test)?);
-        self.trans_state()?;
-        self.sync_commit_status()?;
-
-        Ok(true)
+        let key = approval.time.timestamp().to_string();
+        self.notify(Notification::Approved,
+                    &key,
+                    &[("approver", approval.username),
+                      ("queue_position", queue_position.to_string())]);
     }
 
     pub fn push_merged(&mut self, target_branch: &BranchInfo) -> Result<bool> {
@@ -282,6 +347,13 @@ impl<'a> MergeRequest<'a> {
 
         let test_info = self.test_state.kind().info().cloned().expect("invalid test status");
 
+        if target_branch.gitlab_object_id() == test_info.merge_sha {
+            // Already pushed together with other merge requests in the rollup
+            info!(self.log, "already landed"; "sha" => *test_info.merge_sha.value());
+            self.mark_merged()?;
+            return Ok(true);
+        }
+
         if target_branch.gitlab_object_id() != test_info.target_sha {
             // Retry
             info!(self.log, "test info not matched");
@@ -292,10 +364,7 @@ impl<'a> MergeRequest<'a> {
         }
         // TODO: more checkes
 
-        let repository = self.project.repository();
-
         // Fetch merge branch
-        // TODO: is there any better way?
         let merge_remote_branch = self.project
             .repository_fetch_branch("origin", &test_info.merge_branch)?;
         if test_info.merge_sha != merge_remote_branch.gitlab_object_id() {
@@ -310,86 +379,518 @@ impl<'a> MergeRequest<'a> {
             return Ok(false);
         }
 
-        // Avoid force update current HEAD branch error
-        self.project.repository_reset_branch(&merge_remote_branch.branch)?;
-
-        // Create merge branch
-        let merge_branch =
-            repository.branch(&test_info.merge_branch, &merge_remote_branch.commit, true)?;
-        self.project.repository_reset_branch(&merge_branch)?;
-
-        // Push
-        let merge_branch_ref = merge_branch.get().name().unwrap();
-        let target_branch_name = test_info.target_branch;
-        let refspec = format!("{}:refs/heads/{}", merge_branch_ref, target_branch_name);
-        // TODO: push error not shown (e.g. current user does not have enough permission)
+        // Push the tested commit as is
+        let refspec = format!("{}:refs/heads/{}",
+                              merge_remote_branch.refname,
+                              test_info.target_branch);
         if let Err(e) = self.project.repository_push_branch("origin", &refspec) {
-            // updated remote branch
-            warn!(self.log, "failed to push. target may be updated");
-            super::dump_error(&self.log, &e);
-            self.test_state.update_kind(TestStateKind::Pending);
-            self.trans_state()?;
-            self.sync_commit_status()?;
-            return Ok(false);
+            self.project.metrics().increment(Counter::PushFailed, self.project.label());
+            self.audit(audit::EVENT_PUSH_FAILED, Some(&test_info), Some(&e.to_string()));
+
+            let rejection = match *e.kind() {
+                ErrorKind::PushRejected(_, reason, _) => Some(reason),
+                _ => None,
+            };
+            match rejection {
+                Some(PushRejection::NonFastForward) => {
+                    // Retry
+                    warn!(self.log, "failed to push. target updated");
+                    self.test_state.update_kind(TestStateKind::Pending);
+                    self.trans_state()?;
+                    self.sync_commit_status()?;
+                    return Ok(false);
+                }
+                Some(_) => {
+                    // Permission denials need the configuration to be fixed, and hook declines
+                    // need the merge request (or the hook) to be fixed. Pushing again does not
+                    // help in either case, so the test is failed with the reason (and notified)
+                    // until `retry` is requested
+                    warn!(self.log, "failed to push. push rejected");
+                    let mut info = test_info;
+                    info.push_error = Some(e.to_string());
+                    self.test_state.update_kind(TestStateKind::new_failed(info)?);
+                    self.trans_state()?;
+                    self.sync_commit_status()?;
+                    return Ok(false);
+                }
+                None => return Err(e),
+            }
         }
 
         info!(self.log, "successfully pushed");
-        // TODO: remove source branch
 
+        self.mark_merged()?;
+
+        Ok(true)
+    }
+
+    pub fn is_try_requested(&self) -> bool {
+        self.try_requested
+    }
+
+    pub fn start_try(&mut self, target_branch: &BranchInfo<'a>) -> Result<()> {
+        assert!(self.try_requested);
+        self.try_requested = false;
+
+        let project = self.project;
+        let try_branch_name = format!("{}{}", TRY_BRANCH_PREFIX, self.merge_request.target_branch);
+
+        let (merge_commit, source_sha) =
+            match self.merge_onto(MergeKind::Try, &target_branch.commit)? {
+                Some(result) => result,
+                None => {
+                    self.try_state.update_kind(TestStateKind::Failed(None));
+                    self.sync_commit_status()?;
+                    return Ok(());
+                }
+            };
+
+        let merge_sha = merge_commit.id().to_string();
+        info!(self.log, "successfully merged for try"; "sha" => merge_sha);
+
+        // Force push
+        let try_branch_ref = project.repository_update_local_branch(&try_branch_name,
+                                                                    &merge_commit)?;
+        let refspec = format!("+{}:refs/heads/{}", try_branch_ref, try_branch_name);
+        project.repository_push_branch("origin", &refspec)?;
+        info!(self.log, "successfully pushed");
+
+        // Update status
+        let test = TestStateInfo {
+            build_url: format!("{}/commit/{}/builds", project.project().web_url, merge_sha),
+            merge_sha: ObjectId::new(&merge_sha),
+            merge_branch: try_branch_name,
+            source_project_id: self.merge_request.source_project_id,
+            source_branch: self.merge_request.source_branch.clone(),
+            source_sha: source_sha,
+            target_project_id: self.merge_request.target_project_id,
+            target_branch: self.merge_request.target_branch.clone(),
+            target_sha: target_branch.gitlab_object_id(),
+            batch: vec![],
+            push_error: None,
+            landed: false,
+        };
+
+        self.try_state.update_kind(TestStateKind::new_running(test)?);
+        self.sync_commit_status()?;
+
+        Ok(())
+    }
+
+    pub fn mark_merged(&mut self) -> Result<()> {
         self.merged = true;
+        // Merge requests left opened (e.g. closing rebased ones failed) are loaded as merged by the
+        // landing recorded in the test status, rather than queued and merged again
+        let landed = match *self.test_state.kind() {
+            TestStateKind::Success { ref info, .. } if !info.landed => {
+                let mut info = info.clone();
+                info.landed = true;
+                Some(info)
+            }
+            _ => None,
+        };
+        if let Some(info) = landed {
+            self.test_state.update_kind(TestStateKind::new_success(info)?);
+        }
+        if let (Some(info), Some(approval)) = (self.test_state.info(),
+                                               self.approval_state.kind().info()) {
+            self.record(|store| store.record_merge(&self.merge_request, info, approval));
+            self.audit(audit::EVENT_PUSHED, Some(info), None);
+        }
         self.trans_state()?;
         self.sync_commit_status()?;
+        self.close_landed();
+        self.remove_source_branch();
+        Ok(())
+    }
+
+    /// Closes the landed merge request if GitLab cannot mark it as merged by itself.
+    ///
+    /// GitLab marks merge requests as merged when their head commits are pushed to the target
+    /// branch, which never happens to rebased or squashed commits. Accepting the merge request via
+    /// the API is not an option since GitLab would merge it once again.
+    fn close_landed(&mut self) {
+        match self.project.repo_config().merge_strategy {
+            MergeStrategy::Merge |
+            MergeStrategy::FastForwardOnly => return,
+            MergeStrategy::Rebase |
+            MergeStrategy::Squash => {}
+        }
+        let merge_sha = match self.test_state.info() {
+            Some(info) => info.merge_sha.value().clone(),
+            None => return,
+        };
+
+        // Failures only leave the merge request opened
+        match self.close_if_opened() {
+            Ok(true) => {
+                info!(self.log, "merge request closed"; "sha" => merge_sha);
+                self.notify(Notification::Closed, &merge_sha, &[("merge_sha", merge_sha.clone())]);
+            }
+            Ok(false) => debug!(self.log, "merge request already closed"),
+            Err(e) => {
+                warn!(self.log, "failed to close merge request");
+                super::dump_error(&self.log, &e);
+            }
+        }
+    }
 
+    fn close_if_opened(&self) -> Result<bool> {
+        let gitlab = self.project.gitlab();
+        let project_id = self.merge_request.target_project_id;
+        let id = self.merge_request.id;
+
+        let mr = gitlab.gitlab().merge_request(project_id, id)?;
+        match mr.state {
+            gitlab::MergeRequestState::Opened |
+            gitlab::MergeRequestState::Reopened => {}
+            _ => return Ok(false),
+        }
+
+        gitlab.close_merge_request(project_id, id)?;
         Ok(true)
     }
 
+    /// Removes the source branch if requested in the merge request or by the repository config.
+    ///
+    /// Failures are reported by a merge request note and do not affect the merge request status.
+    fn remove_source_branch(&mut self) {
+        let requested = self.merge_request.force_remove_source_branch == Some(true) ||
+                        self.merge_request.should_remove_source_branch == Some(true) ||
+                        self.project.repo_config().remove_source_branch;
+        if !requested {
+            return;
+        }
+        // Branches of forked projects are left to their owners
+        if self.merge_request.source_project_id != self.merge_request.target_project_id {
+            debug!(self.log, "source branch of other project is not removed");
+            return;
+        }
+
+        let source_branch = self.merge_request.source_branch.clone();
+        match self.delete_source_branch() {
+            Ok(()) => info!(self.log, "source branch removed"; "branch" => source_branch),
+            Err(e) => {
+                warn!(self.log, "failed to remove source branch"; "branch" => source_branch);
+                super::dump_error(&self.log, &e);
+                let key = self.merge_request.sha.value().clone();
+                self.notify(Notification::SourceBranchNotRemoved,
+                            &key,
+                            &[("source_branch", source_branch), ("error", e.to_string())]);
+            }
+        }
+    }
+
+    fn delete_source_branch(&self) -> Result<()> {
+        let gitlab = self.project.gitlab();
+        let project_id = self.merge_request.source_project_id;
+
+        let branch = gitlab.repository_branch(project_id, &self.merge_request.source_branch)?;
+        if branch.protected {
+            bail!("source branch is protected");
+        }
+        // Commits pushed after the test must not be lost
+        let tested_sha = self.test_state.info().map(|info| info.source_sha.value().clone());
+        if tested_sha.as_ref() != Some(&branch.commit.id) {
+            bail!("source branch has been updated since tested");
+        }
+
+        gitlab.delete_branch(project_id, &branch.name)
+    }
+
+    /// Applies the merge request onto `parent` in memory and returns the created commit and the
+    /// merged source commit, or `None` if conflicted.
+    fn merge_onto(&self,
+                  kind: MergeKind,
+                  parent: &Commit<'a>)
+                  -> Result<Option<(Commit<'a>, ObjectId)>> {
+        let project = self.project;
+        let source_project =
+            project.gitlab().gitlab().project(self.merge_request.source_project_id)?;
+
+        // Fetch source branch
+        let source_branch =
+            project.repository_fetch_merge_request(&source_project, &self.merge_request)?;
+        let source = &source_branch.commit;
+
+        let commit = match project.repo_config().merge_strategy {
+            MergeStrategy::Merge => self.merge_commit(kind, parent, source, &source_project)?,
+            MergeStrategy::Rebase => self.rebase_commits(parent, source)?,
+            MergeStrategy::Squash => self.squash_commits(kind, parent, source, &source_project)?,
+            MergeStrategy::FastForwardOnly => self.fast_forward(parent, source)?,
+        };
+
+        if commit.is_none() {
+            info!(self.log, "conflicted!");
+        }
+        Ok(commit.map(|commit| (commit, source_branch.gitlab_object_id())))
+    }
+
+    fn merge_commit(&self,
+                    kind: MergeKind,
+                    parent: &Commit<'a>,
+                    source: &Commit<'a>,
+                    source_project: &gitlab::Project)
+                    -> Result<Option<Commit<'a>>> {
+        let repository = self.project.repository();
+
+        let mut index = repository.merge_commits(parent, source, None)?;
+        if index.has_conflicts() {
+            return Ok(None);
+        }
+
+        // Commit
+        let sig = self.merge_commit_signature()?;
+        let message = self.merge_commit_message(kind, source_project);
+        let tree = repository.find_tree(index.write_tree_to(repository)?)?;
+        let merge_commit_oid =
+            repository.commit(None, &sig, &sig, &message, &tree, &[parent, source])?;
+
+        let merge_commit = repository.find_commit(merge_commit_oid)?;
+        Ok(Some(merge_commit))
+    }
+
+    // Replays the source commits not in `parent` one by one. Merge commits are replayed relative
+    // to their first parent, and root commits relative to the empty tree.
+    fn rebase_commits(&self,
+                      parent: &Commit<'a>,
+                      source: &Commit<'a>)
+                      -> Result<Option<Commit<'a>>> {
+        let repository = self.project.repository();
+        let sig = self.merge_commit_signature()?;
+
+        let mut revwalk = repository.revwalk()?;
+        revwalk.set_sorting(git2::SORT_TOPOLOGICAL | git2::SORT_REVERSE);
+        revwalk.push(source.id())?;
+        revwalk.hide(parent.id())?;
+        let oids = revwalk.collect::<::std::result::Result<Vec<Oid>, _>>()?;
+
+        let mut head = parent.clone();
+        for oid in oids {
+            let commit = repository.find_commit(oid)?;
+            let base_tree = match commit.parents().next() {
+                Some(parent) => parent.tree()?,
+                None => repository.find_tree(repository.treebuilder(None)?.write()?)?,
+            };
+            let mut index =
+                repository.merge_trees(&base_tree, &head.tree()?, &commit.tree()?, None)?;
+            if index.has_conflicts() {
+                debug!(self.log, "rebase conflicted"; "sha" => oid.to_string());
+                return Ok(None);
+            }
+
+            let tree = repository.find_tree(index.write_tree_to(repository)?)?;
+            let message = commit.message().unwrap_or_default();
+            let new_oid = repository.commit(None,
+                                            &commit.author(),
+                                            &sig,
+                                            message,
+                                            &tree,
+                                            &[&head])?;
+            head = repository.find_commit(new_oid)?;
+        }
+
+        Ok(Some(head))
+    }
+
+    fn squash_commits(&self,
+                      kind: MergeKind,
+                      parent: &Commit<'a>,
+                      source: &Commit<'a>,
+                      source_project: &gitlab::Project)
+                      -> Result<Option<Commit<'a>>> {
+        let repository = self.project.repository();
+
+        let base = repository.find_commit(repository.merge_base(parent.id(), source.id())?)?;
+        let mut index =
+            repository.merge_trees(&base.tree()?, &parent.tree()?, &source.tree()?, None)?;
+        if index.has_conflicts() {
+            return Ok(None);
+        }
+
+        let tree = repository.find_tree(index.write_tree_to(repository)?)?;
+        let author = self.squash_commit_author(parent, source)?;
+        let committer = self.merge_commit_signature()?;
+        let message = self.merge_commit_message(kind, source_project);
+        let oid = repository.commit(None, &author, &committer, &message, &tree, &[parent])?;
+
+        Ok(Some(repository.find_commit(oid)?))
+    }
+
+    // Email addresses of GitLab users are not always visible, so they are taken from the source
+    // commits authored by the merge request author
+    fn squash_commit_author(&self, parent: &Commit<'a>, source: &Commit<'a>) -> Result<Signature> {
+        let repository = self.project.repository();
+        let author = &self.merge_request.author;
+
+        let mut revwalk = repository.revwalk()?;
+        revwalk.push(source.id())?;
+        revwalk.hide(parent.id())?;
+
+        let mut email = source.author().email().map(|s| s.to_string());
+        for oid in revwalk {
+            let commit = repository.find_commit(oid?)?;
+            if commit.author().name() == Some(author.name.as_str()) {
+                email = commit.author().email().map(|s| s.to_string());
+                break;
+            }
+        }
+
+        Ok(Signature::now(&author.name, &email.unwrap_or_default())?)
+    }
+
+    // The source branch itself is tested and pushed if it is based on `parent`
+    fn fast_forward(&self,
+                    parent: &Commit<'a>,
+                    source: &Commit<'a>)
+                    -> Result<Option<Commit<'a>>> {
+        let repository = self.project.repository();
+
+        if source.id() != parent.id() &&
+           repository.merge_base(parent.id(), source.id())? != parent.id() {
+            info!(self.log, "source branch is not based on the target branch");
+            return Ok(None);
+        }
+
+        Ok(Some(source.clone()))
+    }
+
     fn merge_commit_signature(&self) -> Result<Signature> {
         let current_user = self.project.gitlab().current_user();
         let sig = Signature::now(&current_user.name, &current_user.email)?;
         Ok(sig)
     }
 
-    fn merge_commit_message(&self, source_project: &gitlab::Project) -> String {
-        let approval = self.approval_state.kind().info().expect("invalid approval status");
-        let mr_desc =
-            self.merge_request.description.as_ref().map(|s| s.as_str()).unwrap_or_default();
-
-        let summary = format!("Auto merge of !{} - {}:{}, r={}",
-                self.merge_request.id,
-                source_project.namespace.name,
-                self.merge_request.source_branch,
-                approval.username
-        );
-        let desc = format!("{}\n\n{}",
-                              self.merge_request.title,
-                              mr_desc);
-        let appendix = format!("See merge request !{}", self.merge_request.id);
+    fn merge_commit_message(&self, kind: MergeKind, source_project: &gitlab::Project) -> String {
+        let messages = &self.project.repo_config().commit_messages;
+        let template = match (kind, self.project.repo_config().merge_strategy) {
+            (MergeKind::Try, _) => &messages.try_merge,
+            (MergeKind::Auto, MergeStrategy::Squash) => &messages.squash,
+            (MergeKind::Auto, _) => &messages.merge,
+        };
 
-        format!("{}\n\n{}\n\n{}",
-                summary,
-                desc.trim_right(),
-                appendix)
+        let mr = &self.merge_request;
+        let description = strip_description(mr.description.as_ref().map_or("", |s| s.as_str()),
+                                            &messages.strip_sections,
+                                            messages.strip_task_lists);
+        let approver = self.approval_state.kind().info().map(|approval| approval.username.clone());
+
+        let mut values = HashMap::new();
+        let _ = values.insert("id", mr.id.value().to_string());
+        let _ = values.insert("iid", mr.iid.value().to_string());
+        let _ = values.insert("title", mr.title.clone());
+        let _ = values.insert("description", description);
+        let _ = values.insert("source_namespace", source_project.namespace.name.clone());
+        let _ = values.insert("source_branch", mr.source_branch.clone());
+        let _ = values.insert("target_branch", mr.target_branch.clone());
+        let _ = values.insert("approver", approver.unwrap_or_default());
+        let _ = values.insert("author", mr.author.username.clone());
+        let _ = values.insert("url", self.web_url());
+
+        normalize_message(&template.render(&values))
     }
 
     fn update_approval_status(&mut self) -> Result<()> {
-        let next_kind = {
-            let gitlab::MergeRequest { source_project_id, ref sha, .. } = self.merge_request;
+        let project = self.project;
+        let gitlab = project.gitlab();
+        let me = gitlab.current_user();
+
+        let id = self.merge_request.id;
+        let source_project_id = self.merge_request.source_project_id;
+        let target_project_id = self.merge_request.target_project_id;
+        let sha = self.merge_request.sha.clone();
+
+        let commit_comments = gitlab.gitlab().commit_comments(source_project_id, sha.value())?;
+        let notes = gitlab.merge_request_notes(target_project_id, id)?;
+        let delegation = Delegation::from_notes(&notes, me);
+        self.notified = notes.iter()
+            .filter(|note| note.author.id == me.id)
+            .flat_map(|note| note.body.lines())
+            .map(|line| line.trim())
+            .filter(|line| line.starts_with(NOTIFICATION_MARKER))
+            .map(|line| line.to_string())
+            .collect();
+
+        // Notes written before the current head commit is pushed are for the older commits.
+        // Versions may lag behind pushes, in which case all notes are read
+        let pushed_at = gitlab.merge_request_versions(target_project_id, id)?
+            .into_iter()
+            .find(|version| version.head_commit_sha == *sha.value())
+            .map(|version| version.created_at);
+        if pushed_at.is_none() {
+            warn!(self.log, "merge request version not found. notes are not filtered";
+                  "sha" => *sha.value());
+        }
 
-            let gitlab = self.project.gitlab();
+        let mut comments = commit_comments.iter()
+            .map(|c| {
+                Comment {
+                    author: &c.author,
+                    body: &c.note,
+                    created_at: c.created_at,
+                }
+            })
+            .chain(notes.iter()
+                .filter(|n| !n.system && n.author.id != me.id)
+                .filter(|n| pushed_at.map_or(true, |pushed_at| n.created_at >= pushed_at))
+                .map(|n| {
+                    Comment {
+                        author: &n.author,
+                        body: &n.body,
+                        created_at: n.created_at,
+                    }
+                }))
+            .collect::<Vec<_>>();
+        comments.sort_by_key(|c| c.created_at);
+
+        let commands = parse_comments(&comments,
+                                      &me.username,
+                                      &self.merge_request.author,
+                                      delegation.clone(),
+                                      |c| project.is_reviewer(c.author))?;
+
+        let delegatee = commands.delegation.as_ref().map(|d| &d.username);
+        if delegatee != delegation.as_ref().map(|d| &d.username) {
+            info!(self.log, "approval delegation updated";
+                  "before" => delegation.as_ref().map(|d| d.username.as_str()),
+                  "after" => delegatee.map(|s| s.as_str()));
+            let note = Delegation::to_note(commands.delegation.as_ref());
+            let _ = gitlab.create_merge_request_note(target_project_id, id, &note)?;
+        }
 
-            let comments = gitlab.gitlab().commit_comments(source_project_id, sha.value())?;
-            let reviewer_comments = comments.into_iter()
-                .filter(|c| self.project.is_reviewer(c.author.id))
-                .collect::<Vec<_>>();
+        for &(ref username, time) in &commands.unauthorized {
+            info!(self.log, "approval by non-reviewer ignored"; "user" => username.as_str());
+            let key = format!("{}:{}", username, time.timestamp());
+            self.notify(Notification::Unauthorized, &key, &[("user", username.clone())]);
+        }
+
+        let next_kind = commands.approval;
+        self.test_request = commands.test_request;
 
-            parse_comments(&reviewer_comments, gitlab.current_user())?
+        // Requests older than the last try status have already been handled
+        let last_try = self.pipeline_state.get(TryState::status_name()).map(|s| s.created_at);
+        self.try_requested = match (commands.try_request, last_try) {
+            (Some(requested), Some(last_try)) => requested > last_try,
+            (Some(_), None) => true,
+            (None, _) => false,
         };
 
         if next_kind != *self.approval_state.kind() {
             debug!(self.log, "approval status updated via GitLab comments";
                    "before" => *self.approval_state.kind(),
                    "after" => next_kind);
+            self.record(|store| {
+                store.record_approval(&self.merge_request, next_kind.info())
+            });
+            let is_revoked = next_kind.info().is_none();
+            if is_revoked {
+                // Revocations are recorded with the previous approver
+                self.audit(audit::EVENT_REVOKED, None, None);
+            }
             self.approval_state.update_kind(next_kind);
+            if !is_revoked {
+                self.audit(audit::EVENT_APPROVED, None, None);
+            }
             self.trans_state()?;
         } else {
             debug!(self.log, "approval status not updated via GitLab comments";
@@ -400,7 +901,7 @@ impl<'a> MergeRequest<'a> {
     }
 
     fn update_test_status(&mut self) -> Result<()> {
-        let info = if let Some(info) = self.test_state.info() {
+        let info = if let Some(info) = self.running_test_info() {
             info.clone()
         } else {
             debug!(self.log, "test status not updated via GitLab build status";
@@ -416,7 +917,7 @@ impl<'a> MergeRequest<'a> {
            info.source_branch != self.merge_request.source_branch ||
            info.target_project_id != self.merge_request.target_project_id ||
            info.target_branch != self.merge_request.target_branch {
-            // TODO: Cancel current running build
+            self.cancel_test(&info)?;
             let next_kind = TestStateKind::Pending;
             info!(self.log, "test status updated via merge request status";
                       "before" => *self.test_state.kind(),
@@ -426,25 +927,22 @@ impl<'a> MergeRequest<'a> {
             return Ok(());
         }
 
-        let next_kind = if builds.iter()
-            .any(|b| b.status == StatusState::Pending || b.status == StatusState::Running) {
-            TestStateKind::new_running(info)?
-        } else if builds.iter().any(|b| b.status == StatusState::Canceled) {
-            TestStateKind::new_canceled(info)?
-        } else if builds.iter().any(|b| b.status == StatusState::Failed) {
-            TestStateKind::new_failed(info)?
-        } else if builds.iter().all(|b| b.status == StatusState::Success) {
-            TestStateKind::new_success(info)?
-        } else {
-            warn!(self.log, "odd build statuses";
-                  "status" => format!("{:?}", builds.iter().map(|b| b.status).collect::<Vec<_>>()));
-            TestStateKind::new_running(info)?
+        let statuses = builds.iter().map(|b| b.status).collect::<Vec<_>>();
+        let next_kind = match aggregate_build_status(&self.log, &statuses) {
+            StatusState::Pending | StatusState::Running => TestStateKind::new_running(info)?,
+            StatusState::Canceled => TestStateKind::new_canceled(info)?,
+            StatusState::Failed if info.batch.len() > 1 => TestStateKind::new_bisecting(info)?,
+            StatusState::Failed => TestStateKind::new_failed(info)?,
+            StatusState::Success => TestStateKind::new_success(info)?,
         };
 
         if next_kind != *self.test_state.kind() {
             debug!(self.log, "test status updated via GitLab build status";
                    "before" => *self.test_state.kind(),
                    "after" => next_kind);
+            if !matches!(next_kind, TestStateKind::Running { .. }) {
+                self.audit(audit::EVENT_TEST_FINISHED, next_kind.info(), Some(next_kind.as_str()));
+            }
             self.test_state.update_kind(next_kind);
             self.trans_state()?;
         } else {
@@ -455,15 +953,162 @@ impl<'a> MergeRequest<'a> {
         Ok(())
     }
 
+    fn update_try_status(&mut self) -> Result<()> {
+        let info = if let Some(info) = self.try_state.kind().info() {
+            info.clone()
+        } else {
+            debug!(self.log, "try status not updated via GitLab build status";
+                   "status" => *self.try_state.kind());
+            return Ok(());
+        };
+
+        let gitlab = self.project.gitlab();
+        let builds = gitlab.gitlab()
+            .commit_latest_builds(self.merge_request.target_project_id, info.merge_sha.value())?;
+
+        let statuses = builds.iter().map(|b| b.status).collect::<Vec<_>>();
+        let next_kind = match aggregate_build_status(&self.log, &statuses) {
+            StatusState::Pending | StatusState::Running => TestStateKind::new_running(info)?,
+            StatusState::Canceled => TestStateKind::new_canceled(info)?,
+            StatusState::Failed => TestStateKind::new_failed(info)?,
+            StatusState::Success => TestStateKind::new_success(info)?,
+        };
+
+        if next_kind != *self.try_state.kind() {
+            debug!(self.log, "try status updated via GitLab build status";
+                   "before" => *self.try_state.kind(),
+                   "after" => next_kind);
+            self.try_state.update_kind(next_kind);
+        } else {
+            debug!(self.log, "try status not updated via GitLab build status";
+                   "status" => next_kind);
+        }
+
+        Ok(())
+    }
+
+    fn apply_test_request(&mut self) -> Result<()> {
+        let (request, time) = if let Some(request) = self.test_request.take() {
+            request
+        } else {
+            return Ok(());
+        };
+
+        if !matches!(self.state, State::Failed(Some(_))) {
+            debug!(self.log, "test request ignored";
+                   "request" => request.as_str(),
+                   "status" => self.state);
+            return Ok(());
+        }
+
+        // Requests older than the last test status have already been handled
+        let last_updated = self.pipeline_state.get(TestState::status_name()).map(|s| s.created_at);
+        if last_updated.map_or(false, |updated| updated >= time) {
+            debug!(self.log, "test request already handled"; "request" => request.as_str());
+            return Ok(());
+        }
+
+        // Neither retrying nor rerunning resolves conflicts with the target branch
+        if matches!(self.merge_request.merge_status, MergeStatus::CannotBeMerged) {
+            info!(self.log, "test request refused. merge request has conflicts";
+                  "request" => request.as_str());
+            let key = format!("{}:{}", request.as_str(), time.timestamp());
+            self.notify(Notification::CannotRetry,
+                        &key,
+                        &[("request", request.as_str().into())]);
+            return Ok(());
+        }
+
+        let next_kind = match (request, self.test_state.info().cloned()) {
+            (TestRequest::Rerun, Some(mut info)) => {
+                info.push_error = None;
+                self.rerun_pipeline(&info)?;
+                self.audit(audit::EVENT_TEST_STARTED, Some(&info), Some("rerun"));
+                TestStateKind::new_running(info)?
+            }
+            (TestRequest::Rerun, None) => {
+                info!(self.log, "no test to rerun. retry instead");
+                TestStateKind::Pending
+            }
+            (TestRequest::Retry, _) => TestStateKind::Pending,
+        };
+
+        info!(self.log, "test status updated via test request";
+              "request" => request.as_str(),
+              "before" => *self.test_state.kind(),
+              "after" => next_kind);
+        self.test_state.update_kind(next_kind);
+        self.trans_state()?;
+
+        Ok(())
+    }
+
+    fn rerun_pipeline(&self, info: &TestStateInfo) -> Result<()> {
+        let gitlab = self.project.gitlab();
+        let builds = gitlab.gitlab()
+            .commit_latest_builds(info.target_project_id, info.merge_sha.value())?;
+        let pipeline_id = if let Some(build) = builds.iter()
+            .max_by_key(|b| b.pipeline.id.value()) {
+            build.pipeline.id
+        } else {
+            bail!("pipeline not found: {}", info.merge_sha.value())
+        };
+
+        gitlab.retry_pipeline(info.target_project_id, pipeline_id)?;
+        info!(self.log, "pipeline restarted";
+              "sha" => *info.merge_sha.value(),
+              "pipeline" => pipeline_id.value());
+
+        Ok(())
+    }
+
+    fn cancel_test(&mut self, info: &TestStateInfo) -> Result<()> {
+        if !matches!(*self.test_state.kind(), TestStateKind::Running { .. }) {
+            return Ok(());
+        }
+
+        let gitlab = self.project.gitlab();
+        let builds = gitlab.gitlab()
+            .commit_latest_builds(info.target_project_id, info.merge_sha.value())?;
+        let mut pipeline_ids = builds.iter()
+            .filter(|b| b.status == StatusState::Pending || b.status == StatusState::Running)
+            .map(|b| b.pipeline.id)
+            .collect::<Vec<_>>();
+        pipeline_ids.sort_by_key(|id| id.value());
+        pipeline_ids.dedup();
+
+        for pipeline_id in pipeline_ids {
+            gitlab.cancel_pipeline(info.target_project_id, pipeline_id)?;
+            info!(self.log, "pipeline canceled";
+                  "sha" => *info.merge_sha.value(),
+                  "pipeline" => pipeline_id.value());
+        }
+
+        // Record the cancellation before the test status is reset
+        self.test_state.update_kind(TestStateKind::new_canceled(info.clone())?);
+        sync_commit_status(&self.log,
+                           self.project,
+                           &self.test_state,
+                           &mut self.pipeline_state)?;
+
+        Ok(())
+    }
+
     fn sync_commit_status(&mut self) -> Result<()> {
         sync_commit_status(&self.log,
-                           self.project.gitlab(),
+                           self.project,
                            &self.approval_state,
                            &mut self.pipeline_state)?;
         sync_commit_status(&self.log,
-                           self.project.gitlab(),
+                           self.project,
                            &self.test_state,
                            &mut self.pipeline_state)?;
+        if *self.try_state.kind() != TestStateKind::Pending {
+            sync_commit_status(&self.log,
+                               self.project,
+                               &self.try_state,
+                               &mut self.pipeline_state)?;
+        }
         Ok(())
     }
 
@@ -480,7 +1125,8 @@ impl<'a> MergeRequest<'a> {
 
         if let Some(approval) = approval {
             return match *self.test_state.kind() {
-                TestStateKind::Pending => State::Approved(approval.clone()),
+                TestStateKind::Pending |
+                TestStateKind::Bisecting { .. } => State::Approved(approval.clone()),
                 TestStateKind::Running { .. } => State::Running(approval.clone()),
                 TestStateKind::Success { .. } => {
                     if self.merged {
@@ -505,6 +1151,9 @@ impl<'a> MergeRequest<'a> {
                   "before" => self.state,
                   "after" => next_state);
             self.state = next_state;
+            self.record_metrics();
+            self.notify_state();
+            self.record_state();
         } else {
             debug!(self.log, "merge request status not changed";
                    "status" => next_state);
@@ -512,6 +1161,372 @@ impl<'a> MergeRequest<'a> {
 
         Ok(())
     }
+
+    // The local store is updated on each state transition and when the state loaded from GitLab
+    // differs from the recorded one (the store is outdated, and GitLab wins).
+    fn record_state(&self) {
+        let test_kind = self.test_state.kind();
+        self.record(|store| {
+            if store.record_state(&self.merge_request, &self.state, test_kind.info())? {
+                debug!(self.log, "local store updated"; "state" => self.state);
+            }
+            if let Some(info) = test_kind.info() {
+                store.record_test(&self.merge_request, test_kind.as_str(), info)?;
+            }
+            Ok(())
+        });
+    }
+
+    // Failures of the audit log do not affect the merge request status
+    fn audit(&self, event: &str, test: Option<&TestStateInfo>, result: Option<&str>) {
+        let project = self.project;
+        let audit = if let Some(audit) = project.audit() {
+            audit
+        } else {
+            return;
+        };
+
+        let approval = self.approval_state.kind().info();
+        let record = AuditRecord {
+            time: UTC::now(),
+            event: event.into(),
+            repo: project.label().into(),
+            project: project.repo_config().name.clone(),
+            merge_request: self.merge_request.id.value(),
+            merge_request_iid: self.merge_request.iid.value(),
+            sha: self.merge_request.sha.value().clone(),
+            target_branch: self.merge_request.target_branch.clone(),
+            approver: approval.map(|approval| approval.username.clone()),
+            approved_at: approval.map(|approval| approval.time),
+            source_sha: test.map(|test| test.source_sha.value().clone()),
+            target_sha: test.map(|test| test.target_sha.value().clone()),
+            merge_sha: test.map(|test| test.merge_sha.value().clone()),
+            result: result.map(Into::into),
+        };
+
+        if let Err(e) = audit.append(&record) {
+            warn!(self.log, "failed to write audit log"; "event" => event);
+            super::dump_error(&self.log, &e);
+        }
+    }
+
+    // Failures of the local store do not affect the merge request status
+    fn record<F>(&self, f: F)
+        where F: FnOnce(&Store) -> Result<()>
+    {
+        if let Some(store) = self.project.store() {
+            if let Err(e) = f(store) {
+                warn!(self.log, "failed to update local store");
+                super::dump_error(&self.log, &e);
+            }
+        }
+    }
+
+    fn record_metrics(&self) {
+        let metrics = self.project.metrics();
+        let label = self.project.label();
+        let cannot_be_merged = matches!(self.merge_request.merge_status,
+                                        MergeStatus::CannotBeMerged);
+
+        match (&self.state, self.test_state.info()) {
+            (&State::Merged(ref approval), _) => {
+                metrics.increment(Counter::Merged, label);
+                let duration = (UTC::now() - approval.time).num_seconds();
+                metrics.observe_merge_duration(label, cmp::max(duration, 0) as u64);
+            }
+            (&State::Failed(Some(_)), _) if cannot_be_merged => {
+                metrics.increment(Counter::Conflicted, label)
+            }
+            (&State::Failed(Some(_)), None) => metrics.increment(Counter::Conflicted, label),
+            // Counted as a push failure
+            (&State::Failed(Some(_)), Some(info)) if info.push_error.is_some() => {}
+            (&State::Failed(Some(_)), Some(_)) => metrics.increment(Counter::TestFailed, label),
+            // Failed rollup
+            (&State::Approved(_), _) if self.bisecting_info().is_some() => {
+                metrics.increment(Counter::TestFailed, label)
+            }
+            _ => {}
+        }
+    }
+
+    // Conflicts reported by GitLab are found on load rather than on state transitions. They are
+    // counted and notified unless the note has already been posted
+    fn notify_loaded_conflict(&mut self) {
+        let is_conflicted = match self.state {
+            State::Failed(Some(_)) => {
+                matches!(self.merge_request.merge_status, MergeStatus::CannotBeMerged)
+            }
+            _ => false,
+        };
+        if !is_conflicted {
+            return;
+        }
+
+        let marker = notification_marker(Notification::Conflicted, self.merge_request.sha.value());
+        if !self.notified.contains(&marker) {
+            self.record_metrics();
+            self.notify_state();
+        }
+    }
+
+    fn notify_state(&mut self) {
+        let sha = self.merge_request.sha.value().clone();
+        let info = self.test_state.info().cloned();
+        let cannot_be_merged = matches!(self.merge_request.merge_status,
+                                        MergeStatus::CannotBeMerged);
+        let values = |info: &TestStateInfo| {
+            vec![("merge_sha", info.merge_sha.value().clone()),
+                 ("build_url", info.build_url.clone())]
+        };
+
+        match (self.state.clone(), info) {
+            (State::Failed(Some(_)), Some(ref info)) if info.push_error.is_some() => {
+                let mut values = values(info);
+                values.push(("error", info.push_error.clone().unwrap_or_default()));
+                self.notify(Notification::PushRejected, info.merge_sha.value(), &values)
+            }
+            (State::Running(_), Some(info)) => {
+                self.notify(Notification::TestStarted, info.merge_sha.value(), &values(&info))
+            }
+            (State::Merged(_), Some(info)) => {
+                self.notify(Notification::Merged, info.merge_sha.value(), &values(&info))
+            }
+            (State::Failed(Some(_)), _) if cannot_be_merged => {
+                self.notify(Notification::Conflicted, &sha, &[])
+            }
+            (State::Failed(Some(_)), None) => self.notify(Notification::Conflicted, &sha, &[]),
+            (State::Failed(Some(_)), Some(info)) => {
+                self.notify(Notification::TestFailed, info.merge_sha.value(), &values(&info))
+            }
+            _ => {}
+        }
+    }
+
+    /// Posts the merge request note unless the same notification has already been posted.
+    ///
+    /// Notes are marked with the notification name and `key`, so notifications are not reposted
+    /// after restarts.
+    fn notify(&mut self, notification: Notification, key: &str, values: &[(&str, String)]) {
+        let marker = notification_marker(notification, key);
+        if self.notified.contains(&marker) {
+            debug!(self.log, "already notified"; "notification" => notification.as_str());
+            return;
+        }
+
+        let project = self.project;
+        let messages = &project.repo_config().messages;
+        let template = match notification {
+            Notification::Approved => &messages.approved,
+            Notification::TestStarted => &messages.test_started,
+            Notification::Conflicted => &messages.conflicted,
+            Notification::TestFailed => &messages.test_failed,
+            Notification::Merged => &messages.merged,
+            Notification::Unauthorized => &messages.unauthorized,
+            Notification::PushRejected => &messages.push_rejected,
+            Notification::Closed => &messages.closed,
+            Notification::SourceBranchNotRemoved => &messages.source_branch_not_removed,
+            Notification::CannotRetry => &messages.cannot_retry,
+        };
+
+        let mut map = values.iter().cloned().collect::<HashMap<_, _>>();
+        let _ = map.insert("author", self.merge_request.author.username.clone());
+        let _ = map.insert("sha", self.merge_request.sha.value().clone());
+        let _ = map.insert("target_branch", self.merge_request.target_branch.clone());
+        let _ = map.insert("iid", self.merge_request.iid.value().to_string());
+        let _ = map.insert("url", self.web_url());
+        let message = template.render(&map);
+        if message.trim().is_empty() {
+            return;
+        }
+
+        // Failures of notification do not affect the merge request status
+        let body = format!("{}\n\n{}", message, marker);
+        match project.gitlab()
+            .create_merge_request_note(self.merge_request.target_project_id,
+                                       self.merge_request.id,
+                                       &body) {
+            Ok(_) => {
+                info!(self.log, "notified"; "notification" => notification.as_str());
+                let _ = self.notified.insert(marker);
+            }
+            Err(e) => {
+                warn!(self.log, "failed to notify"; "notification" => notification.as_str());
+                super::dump_error(&self.log, &e);
+            }
+        }
+    }
+}
+
+fn notification_marker(notification: Notification, key: &str) -> String {
+    format!("{}{}:{}{}",
+            NOTIFICATION_MARKER,
+            notification.as_str(),
+            key,
+            NOTIFICATION_MARKER_END)
+}
+
+/// Merges the merge requests one after another onto the target branch in memory, pushes the result
+/// to the merge branch and starts testing the merged commit.
+///
+/// Merge requests conflicting with the target branch are marked as failed. Merge requests
+/// conflicting only with the preceding ones are excluded from this test and stay approved.
+pub fn start_test<'a>(mrs: &mut [MergeRequest<'a>],
+                      target_branch: &BranchInfo<'a>)
+                      -> Result<bool> {
+    let (project, target_branch_name) = if let Some(mr) = mrs.first() {
+        (mr.project, mr.merge_request.target_branch.clone())
+    } else {
+        return Ok(false);
+    };
+    let merge_branch_name = format!("{}{}", MERGE_BRANCH_PREFIX, target_branch_name);
+
+    let mut merge_commit = target_branch.commit.clone();
+    let mut merged = vec![];
+    for (i, mr) in mrs.iter_mut().enumerate() {
+        assert_matches!(mr.state, State::Approved {..});
+        assert_matches!(*mr.test_state.kind(),
+                        TestStateKind::Pending | TestStateKind::Bisecting { .. });
+
+        let result = match mr.merge_onto(MergeKind::Auto, &merge_commit) {
+            Ok(Some((commit, source_sha))) => {
+                merge_commit = commit;
+                merged.push((i, source_sha));
+                Ok(())
+            }
+            Ok(None) if merged.is_empty() => {
+                mr.test_state.update_kind(TestStateKind::Failed(None));
+                mr.trans_state().and_then(|()| mr.sync_commit_status())
+            }
+            Ok(None) => {
+                info!(mr.log, "conflicted with other merge requests. excluded from rollup");
+                Ok(())
+            }
+            Err(e) => Err(e),
+        };
+
+        if let Err(e) = result {
+            warn!(mr.log, "failed to merge");
+            super::dump_error(&mr.log, &e);
+            mr.mark_errored();
+        }
+    }
+
+    if merged.is_empty() {
+        return Ok(false);
+    }
+
+    let merge_sha = merge_commit.id().to_string();
+    info!(project.log(), "successfully merged";
+          "sha" => merge_sha,
+          "merge_requests" => merged.len());
+
+    // Force push
+    let merge_branch_ref =
+        project.repository_update_local_branch(&merge_branch_name, &merge_commit)?;
+    let refspec = format!("+{}:refs/heads/{}", merge_branch_ref, merge_branch_name);
+    project.repository_push_branch("origin", &refspec)?;
+    info!(project.log(), "successfully pushed");
+
+    // Update status
+    let batch = if merged.len() > 1 {
+        merged.iter().map(|&(i, _)| mrs[i].merge_request.id).collect()
+    } else {
+        vec![]
+    };
+
+    for (i, source_sha) in merged {
+        let mr = &mut mrs[i];
+        let test = TestStateInfo {
+            build_url: format!("{}/commit/{}/builds", project.project().web_url, merge_sha),
+            merge_sha: ObjectId::new(&merge_sha),
+            merge_branch: merge_branch_name.clone(),
+            source_project_id: mr.merge_request.source_project_id,
+            source_branch: mr.merge_request.source_branch.clone(),
+            source_sha: source_sha,
+            target_project_id: mr.merge_request.target_project_id,
+            target_branch: mr.merge_request.target_branch.clone(),
+            target_sha: target_branch.gitlab_object_id(),
+            batch: batch.clone(),
+            push_error: None,
+            landed: false,
+        };
+
+        mr.audit(audit::EVENT_TEST_STARTED, Some(&test), None);
+        mr.test_state.update_kind(TestStateKind::new_running(test)?);
+        mr.trans_state()?;
+        mr.sync_commit_status()?;
+    }
+
+    Ok(true)
+}
+
+/// Removes the sections with the given headings (case-insensitive) and optionally task list items
+/// from the merge request description. Lines in fenced code blocks are not headings nor tasks.
+fn strip_description(description: &str, sections: &[String], task_lists: bool) -> String {
+    let mut lines = vec![];
+    let mut skipping_level = None;
+    let mut in_code_block = false;
+
+    for line in description.lines() {
+        let trimmed = line.trim();
+        if trimmed.starts_with("```") || trimmed.starts_with("~~~") {
+            in_code_block = !in_code_block;
+        } else if in_code_block {
+            if skipping_level.is_none() {
+                lines.push(line);
+            }
+            continue;
+        }
+
+        let level = trimmed.chars().take_while(|c| *c == '#').count();
+        if level > 0 {
+            if skipping_level.map_or(false, |skipping| level <= skipping) {
+                skipping_level = None;
+            }
+            if skipping_level.is_none() {
+                let heading = trimmed[level..].trim().to_lowercase();
+                if sections.iter().any(|section| section.to_lowercase() == heading) {
+                    skipping_level = Some(level);
+                }
+            }
+        }
+        if skipping_level.is_some() {
+            continue;
+        }
+
+        let is_task = ["- [ ]", "- [x]", "- [X]", "* [ ]", "* [x]", "* [X]"]
+            .iter()
+            .any(|prefix| trimmed.starts_with(prefix));
+        if task_lists && is_task {
+            continue;
+        }
+
+        lines.push(line);
+    }
+
+    lines.join("\n")
+}
+
+// Trims trailing spaces of lines and blank lines at both ends. Blank lines in the middle are kept
+// as is, since they may be in code blocks of the description
+fn normalize_message(message: &str) -> String {
+    let lines = message.lines().map(|line| line.trim_right()).collect::<Vec<_>>();
+    lines.join("\n").trim_matches('\n').to_string()
+}
+
+fn aggregate_build_status(log: &Logger, statuses: &[StatusState]) -> StatusState {
+    if statuses.iter().any(|s| *s == StatusState::Pending || *s == StatusState::Running) {
+        StatusState::Running
+    } else if statuses.iter().any(|s| *s == StatusState::Canceled) {
+        StatusState::Canceled
+    } else if statuses.iter().any(|s| *s == StatusState::Failed) {
+        StatusState::Failed
+    } else if statuses.iter().all(|s| *s == StatusState::Success) {
+        StatusState::Success
+    } else {
+        warn!(log, "odd build statuses"; "status" => format!("{:?}", statuses));
+        StatusState::Running
+    }
 }
 
 fn last_pipeline_statuses(gitlab: &GitlabExt,
@@ -546,6 +1561,7 @@ fn last_pipeline_statuses(gitlab: &GitlabExt,
 }
 
 fn create_state_from_pipeline<T>(log: &Logger,
+                                 store: Option<&Store>,
                                  merge_request: &gitlab::MergeRequest,
                                  pipeline_state: &HashMap<String, CommitStatus>)
                                  -> T
@@ -568,7 +1584,7 @@ fn create_state_from_pipeline<T>(log: &Logger,
                     super::dump_error(&log, &e);
                     trace!(log, "detail";
                            "gitlab_state" => format!("{:?}", commit_state));
-                    None
+                    store.and_then(|store| restore_state(&log, store, project_id, commit_state))
                 }
             }
         })
@@ -582,59 +1598,289 @@ fn create_state_from_pipeline<T>(log: &Logger,
     state
 }
 
-#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+const DELEGATE_MARKER: &'static str = "<!-- jaba:delegate=";
+const DELEGATE_MARKER_END: &'static str = " -->";
+
+#[derive(Debug, Clone, Eq, PartialEq)]
 enum Command {
-    Approve(u64),
+    Approve(u64, Option<Rollup>),
     CancelApprove,
+    Retry,
+    Rerun,
+    Rollup(Rollup),
+    Try,
+    DelegateToAuthor,
+    Delegate(String),
+    CancelDelegate,
+}
+
+/// Commit comment or merge request note
+#[derive(Debug)]
+struct Comment<'a> {
+    author: &'a UserBasic,
+    body: &'a str,
+    created_at: DateTime<UTC>,
+}
+
+/// Delegation of approval to a non-reviewer user, effective for comments after `time`
+#[derive(Debug, Clone, Eq, PartialEq)]
+struct Delegation {
+    username: String,
+    time: DateTime<UTC>,
+}
+
+impl Delegation {
+    // Delegation is persisted in the merge request notes posted by jaba since commit comments are
+    // lost when the source branch is updated
+    fn from_notes(notes: &[MergeRequestNote], me: &UserFull) -> Option<Self> {
+        notes.iter()
+            .filter(|note| note.author.id == me.id)
+            .filter_map(|note| {
+                note.body.find(DELEGATE_MARKER).and_then(|pos| {
+                    let rest = &note.body[pos + DELEGATE_MARKER.len()..];
+                    rest.find(DELEGATE_MARKER_END).map(|len| (&rest[..len], note.created_at))
+                })
+            })
+            .last()
+            .and_then(|(username, time)| {
+                if username.is_empty() {
+                    None
+                } else {
+                    Some(Delegation {
+                        username: username.to_string(),
+                        time: time,
+                    })
+                }
+            })
+    }
+
+    fn to_note(delegation: Option<&Self>) -> String {
+        match delegation {
+            Some(delegation) => {
+                format!("Approval delegated to @{}.\n\n{}{}{}",
+                        delegation.username,
+                        DELEGATE_MARKER,
+                        delegation.username,
+                        DELEGATE_MARKER_END)
+            }
+            None => {
+                format!("Approval delegation revoked.\n\n{}{}",
+                        DELEGATE_MARKER,
+                        DELEGATE_MARKER_END)
+            }
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum TestRequest {
+    Retry,
+    Rerun,
+}
+
+impl TestRequest {
+    fn as_str(&self) -> &'static str {
+        match *self {
+            TestRequest::Retry => "retry",
+            TestRequest::Rerun => "rerun",
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Commands {
+    approval: ApprovalStateKind,
+    test_request: Option<(TestRequest, DateTime<UTC>)>,
+    try_request: Option<DateTime<UTC>>,
+    delegation: Option<Delegation>,
+    unauthorized: Vec<(String, DateTime<UTC>)>,
+}
+
+fn parse_rollup(word: &str) -> Option<Rollup> {
+    match word {
+        "rollup" | "rollup=always" => Some(Rollup::Always),
+        "rollup=maybe" => Some(Rollup::Maybe),
+        "rollup=never" => Some(Rollup::Never),
+        _ => None,
+    }
 }
 
-fn parse_command(command: &str, me: &UserFull) -> Option<Command> {
-    let mention = format!("@{}", me.username);
-    let mut words = command.split_whitespace().skip_while(|s| *s != mention).skip(1);
+// Mentions are case-insensitive as GitLab usernames
+fn parse_command(command: &str, me: &str) -> Option<Command> {
+    let mention = format!("@{}", me.to_lowercase());
+    let mut words = command.split_whitespace().skip_while(|s| s.to_lowercase() != mention).skip(1);
 
     words.next().and_then(|word| {
         match word {
             "r+" => {
-                let priority = words.next()
-                    .and_then(|s| {
-                        if s.starts_with("p=") {
-                            s.trim_left_matches("p=").parse::<u64>().ok()
-                        } else {
-                            None
+                let mut priority = 0;
+                let mut rollup = None;
+                for word in words {
+                    if word.starts_with("p=") {
+                        if let Ok(p) = word.trim_left_matches("p=").parse::<u64>() {
+                            priority = p;
                         }
-                    })
-                    .unwrap_or(0);
-                Some(Command::Approve(priority))
+                    } else if let Some(r) = parse_rollup(word) {
+                        rollup = Some(r);
+                    } else {
+                        break;
+                    }
+                }
+                Some(Command::Approve(priority, rollup))
             }
             "r-" => Some(Command::CancelApprove),
-            _ => None,
+            "retry" => Some(Command::Retry),
+            "rerun" => Some(Command::Rerun),
+            "try" => Some(Command::Try),
+            "delegate+" => Some(Command::DelegateToAuthor),
+            "delegate-" => Some(Command::CancelDelegate),
+            word if word.starts_with("delegate=") => {
+                let username = word.trim_left_matches("delegate=").trim_left_matches('@');
+                if username.is_empty() {
+                    None
+                } else {
+                    Some(Command::Delegate(username.to_string()))
+                }
+            }
+            word => parse_rollup(word).map(Command::Rollup),
         }
     })
 }
 
-fn parse_comments<'a, I>(comments: I, me: &UserFull) -> Result<ApprovalStateKind>
-    where I: IntoIterator<Item = &'a CommitNote>
+/// Parses commands in comments.
+///
+/// Comments by reviewers may contain any commands. Comments by the delegatee may contain any
+/// commands except delegation ones, and comments by the merge request author may contain `try`.
+fn parse_comments<'a, I, F>(comments: I,
+                            me: &str,
+                            author: &UserBasic,
+                            delegation: Option<Delegation>,
+                            is_reviewer: F)
+                            -> Result<Commands>
+    where I: IntoIterator<Item = &'a Comment<'a>>,
+          F: Fn(&Comment) -> bool
 {
-    let mut kind = ApprovalStateKind::NotApproved;
+    let mut delegation = delegation;
+    let mut approval = None;
+    let mut rollup = Rollup::default();
+    let mut test_request = None;
+    let mut try_request = None;
+    let mut unauthorized = vec![];
     for comment in comments {
-        if let Some(command) = parse_command(&comment.note, me) {
+        if let Some(command) = parse_command(comment.body, me) {
+            let is_delegation = match command {
+                Command::DelegateToAuthor |
+                Command::Delegate(_) |
+                Command::CancelDelegate => true,
+                _ => false,
+            };
+            let is_delegatee = delegation.as_ref().map_or(false, |d| {
+                d.username == comment.author.username && d.time <= comment.created_at
+            });
+            let is_author = comment.author.id == author.id;
+            let is_allowed = is_reviewer(comment) || (is_delegatee && !is_delegation) ||
+                             (is_author && command == Command::Try);
+            if !is_allowed {
+                if let Command::Approve(..) = command {
+                    unauthorized.push((comment.author.username.clone(), comment.created_at));
+                }
+                continue;
+            }
+
             match command {
-                Command::Approve(p) => {
-                    kind = ApprovalStateKind::new_approved(ApprovalStateInfo {
+                Command::Approve(p, r) => {
+                    approval = Some(ApprovalStateInfo {
                         priority: p,
                         time: comment.created_at,
                         username: comment.author.username.clone(),
-                    })?;
+                        rollup: rollup,
+                    });
+                    if let Some(r) = r {
+                        rollup = r;
+                    }
                 }
-                Command::CancelApprove => kind = ApprovalStateKind::NotApproved,
+                Command::CancelApprove => {
+                    approval = None;
+                    rollup = Rollup::default();
+                    test_request = None;
+                }
+                Command::Retry => test_request = Some((TestRequest::Retry, comment.created_at)),
+                Command::Rerun => test_request = Some((TestRequest::Rerun, comment.created_at)),
+                Command::Rollup(r) => rollup = r,
+                Command::Try => try_request = Some(comment.created_at),
+                Command::DelegateToAuthor => {
+                    delegation = Some(Delegation {
+                        username: author.username.clone(),
+                        time: comment.created_at,
+                    })
+                }
+                Command::Delegate(username) => {
+                    delegation = Some(Delegation {
+                        username: username,
+                        time: comment.created_at,
+                    })
+                }
+                Command::CancelDelegate => delegation = None,
             }
         }
     }
-    Ok(kind)
+
+    let kind = match approval {
+        Some(mut approval) => {
+            approval.rollup = rollup;
+            ApprovalStateKind::new_approved(approval)?
+        }
+        None => ApprovalStateKind::NotApproved,
+    };
+
+    Ok(Commands {
+        approval: kind,
+        test_request: test_request,
+        try_request: try_request,
+        delegation: delegation,
+        unauthorized: unauthorized,
+    })
+}
+
+// Restores the state from the commit status description recorded in the local store, which is not
+// truncated by GitLab
+fn restore_state<T>(log: &Logger,
+                    store: &Store,
+                    project_id: ProjectId,
+                    commit_state: &CommitStatus)
+                    -> Option<T>
+    where T: BuildState
+{
+    let description = match store.commit_status_description(project_id,
+                                                            commit_state.sha.value(),
+                                                            T::status_name(),
+                                                            commit_state.status.as_str()) {
+        Ok(Some(description)) => description,
+        Ok(None) => return None,
+        Err(e) => {
+            warn!(log, "failed to read local store");
+            super::dump_error(log, &e);
+            return None;
+        }
+    };
+
+    let mut commit_state = commit_state.clone();
+    commit_state.description = Some(description);
+    match T::from_commit_status(project_id, &commit_state) {
+        Ok(state) => {
+            info!(log, "commit status restored from local store");
+            Some(state)
+        }
+        Err(e) => {
+            warn!(log, "failed to parse commit status in local store");
+            super::dump_error(log, &e);
+            None
+        }
+    }
 }
 
 fn sync_commit_status<T>(log: &Logger,
-                         gitlab: &GitlabExt,
+                         project: &Project,
                          state: &T,
                          pipeline_state: &mut HashMap<String, CommitStatus>)
                          -> Result<()>
@@ -646,15 +1892,17 @@ fn sync_commit_status<T>(log: &Logger,
     match pipeline_state.entry(name.into()) {
         Entry::Vacant(e) => {
             trace!(log, "no status found on GitLab. do sync.");
-            let new_state = state.sync(gitlab, None)?;
+            let new_state = state.sync(project.gitlab(), None)?;
             let _ = e.insert(new_state);
+            record_commit_status(log, project, state);
         }
         Entry::Occupied(mut e) => {
             let v = e.get_mut();
             if state.need_sync(v) {
                 trace!(log, "override exisiting state.");
-                let new_state = state.sync(gitlab, Some(v.status))?;
+                let new_state = state.sync(project.gitlab(), Some(v.status))?;
                 *v = new_state;
+                record_commit_status(log, project, state);
             } else {
                 trace!(log, "nothing to do.");
             }
@@ -666,3 +1914,290 @@ fn sync_commit_status<T>(log: &Logger,
 
     Ok(())
 }
+
+fn record_commit_status<T>(log: &Logger, project: &Project, state: &T)
+    where T: BuildState
+{
+    if let Some(store) = project.store() {
+        let info = state.to_commit_status_info();
+        if let Err(e) = store.record_commit_status(state.project_id(),
+                                                   state.sha().value(),
+                                                   T::status_name(),
+                                                   state.to_status_state().as_str(),
+                                                   info.description) {
+            warn!(log, "failed to update local store");
+            super::dump_error(log, &e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{Duration, TimeZone};
+    use serde_json;
+
+    fn user(id: u64, username: &str) -> UserBasic {
+        let json = format!(r#"{{
+            "id": {id},
+            "username": "{username}",
+            "name": "{username}",
+            "state": "active",
+            "avatar_url": "https://gitlab.example.com/uploads/user/avatar/{id}/avatar.png",
+            "web_url": "https://gitlab.example.com/{username}"
+        }}"#,
+                           id = id,
+                           username = username);
+        serde_json::from_str(&json).unwrap()
+    }
+
+    fn comment<'a>(author: &'a UserBasic, body: &'a str, minutes: i64) -> Comment<'a> {
+        Comment {
+            author: author,
+            body: body,
+            created_at: UTC.ymd(2017, 3, 1).and_hms(0, 0, 0) + Duration::minutes(minutes),
+        }
+    }
+
+    #[test]
+    fn parse_commands() {
+        let cases = [("@jaba r+", Some(Command::Approve(0, None))),
+                     ("@jaba r+ p=2", Some(Command::Approve(2, None))),
+                     ("@jaba r+ rollup=never p=1",
+                      Some(Command::Approve(1, Some(Rollup::Never)))),
+                     ("@jaba r+ rollup", Some(Command::Approve(0, Some(Rollup::Always)))),
+                     ("@jaba r+ p=high", Some(Command::Approve(0, None))),
+                     ("@jaba r+ thanks p=3", Some(Command::Approve(0, None))),
+                     ("@jaba r-", Some(Command::CancelApprove)),
+                     ("@jaba retry", Some(Command::Retry)),
+                     ("@jaba rerun", Some(Command::Rerun)),
+                     ("@jaba try", Some(Command::Try)),
+                     ("@jaba rollup", Some(Command::Rollup(Rollup::Always))),
+                     ("@jaba rollup=always", Some(Command::Rollup(Rollup::Always))),
+                     ("@jaba rollup=maybe", Some(Command::Rollup(Rollup::Maybe))),
+                     ("@jaba rollup=never", Some(Command::Rollup(Rollup::Never))),
+                     ("@jaba rollup=sometimes", None),
+                     ("@jaba delegate+", Some(Command::DelegateToAuthor)),
+                     ("@jaba delegate-", Some(Command::CancelDelegate)),
+                     ("@jaba delegate=alice", Some(Command::Delegate("alice".into()))),
+                     ("@jaba delegate=@alice", Some(Command::Delegate("alice".into()))),
+                     ("@jaba delegate=", None),
+                     // Approving on behalf of another user is not supported
+                     ("@jaba r=alice", None),
+                     // Whitespace and mention positions
+                     ("Looks good. @jaba r+", Some(Command::Approve(0, None))),
+                     ("  @jaba\n\tretry  \n", Some(Command::Retry)),
+                     ("@alice @jaba try", Some(Command::Try)),
+                     ("r+ @jaba", None),
+                     ("@jaba", None),
+                     // Mentions are case-insensitive, commands are not
+                     ("@Jaba r+", Some(Command::Approve(0, None))),
+                     ("@JABA retry", Some(Command::Retry)),
+                     ("@jaba R+", None),
+                     ("@jaba Retry", None),
+                     // Other users
+                     ("@alice r+", None),
+                     ("@jaba-dev r+", None),
+                     ("jaba r+", None)];
+        for &(command, ref expected) in &cases {
+            assert_eq!(parse_command(command, "jaba"), *expected, "{:?}", command);
+        }
+    }
+
+    #[test]
+    fn parse_comments_approval() {
+        let (alice, bob, carol) = (user(1, "alice"), user(2, "bob"), user(3, "carol"));
+        let comments = [comment(&alice, "@jaba r+ p=1 rollup=never", 0),
+                        comment(&carol, "@jaba r+", 1),
+                        comment(&carol, "@jaba retry", 2)];
+        let commands = parse_comments(&comments,
+                                      "jaba",
+                                      &bob,
+                                      None,
+                                      |c| c.author.username == "alice")
+            .unwrap();
+        let approval = commands.approval.info().unwrap();
+        assert_eq!(approval.username, "alice");
+        assert_eq!(approval.priority, 1);
+        assert_eq!(approval.rollup, Rollup::Never);
+        assert_eq!(approval.time, comments[0].created_at);
+        assert_eq!(commands.unauthorized,
+                   vec![("carol".to_string(), comments[1].created_at)]);
+        assert_eq!(commands.test_request, None);
+
+        let comments = [comment(&alice, "@jaba r+", 0),
+                        comment(&alice, "@jaba rollup=always", 1),
+                        comment(&alice, "@jaba retry", 2),
+                        comment(&alice, "@jaba r-", 3)];
+        let commands = parse_comments(&comments,
+                                      "jaba",
+                                      &bob,
+                                      None,
+                                      |c| c.author.username == "alice")
+            .unwrap();
+        assert_eq!(commands.approval, ApprovalStateKind::NotApproved);
+        assert_eq!(commands.test_request, None);
+
+        let commands = parse_comments(&comments[..3],
+                                      "jaba",
+                                      &bob,
+                                      None,
+                                      |c| c.author.username == "alice")
+            .unwrap();
+        assert_eq!(commands.approval.info().unwrap().rollup, Rollup::Always);
+        assert_eq!(commands.test_request,
+                   Some((TestRequest::Retry, comments[2].created_at)));
+    }
+
+    #[test]
+    fn parse_comments_delegation() {
+        let (alice, bob, carol) = (user(1, "alice"), user(2, "bob"), user(3, "carol"));
+        let comments = [comment(&bob, "@jaba r+", 0),
+                        comment(&alice, "@jaba delegate+", 1),
+                        comment(&bob, "@jaba delegate=carol", 2),
+                        comment(&bob, "@jaba r+", 3),
+                        comment(&carol, "@jaba rerun", 4)];
+        let commands = parse_comments(&comments,
+                                      "jaba",
+                                      &bob,
+                                      None,
+                                      |c| c.author.username == "alice")
+            .unwrap();
+        assert_eq!(commands.delegation,
+                   Some(Delegation {
+                       username: "bob".into(),
+                       time: comments[1].created_at,
+                   }));
+        assert_eq!(commands.approval.info().unwrap().username, "bob");
+        assert_eq!(commands.approval.info().unwrap().time, comments[3].created_at);
+        assert_eq!(commands.unauthorized,
+                   vec![("bob".to_string(), comments[0].created_at)]);
+        assert_eq!(commands.test_request, None);
+
+        let comments = [comment(&alice, "@jaba delegate=carol", 0),
+                        comment(&carol, "@jaba r+", 1),
+                        comment(&alice, "@jaba delegate-", 2),
+                        comment(&carol, "@jaba r-", 3)];
+        let commands = parse_comments(&comments,
+                                      "jaba",
+                                      &bob,
+                                      None,
+                                      |c| c.author.username == "alice")
+            .unwrap();
+        assert_eq!(commands.delegation, None);
+        assert_eq!(commands.approval.info().unwrap().username, "carol");
+    }
+
+    #[test]
+    fn parse_comments_try() {
+        let (alice, bob) = (user(1, "alice"), user(2, "bob"));
+        let comments = [comment(&bob, "@jaba try", 0), comment(&bob, "@jaba retry", 1)];
+        let commands = parse_comments(&comments,
+                                      "jaba",
+                                      &bob,
+                                      None,
+                                      |c| c.author.username == "alice")
+            .unwrap();
+        assert_eq!(commands.try_request, Some(comments[0].created_at));
+        assert_eq!(commands.test_request, None);
+        assert_eq!(commands.approval, ApprovalStateKind::NotApproved);
+
+        // Only the merge request author may request try builds without approval rights
+        let comments = [comment(&alice, "@jaba try", 0)];
+        let commands = parse_comments(&comments, "jaba", &bob, None, |_| false).unwrap();
+        assert_eq!(commands.try_request, None);
+    }
+
+    #[test]
+    fn strip_description_sections() {
+        let description = "Fixes #12.
+
+## Checklist
+
+- [x] Tests added
+
+## Details
+
+Moves the parser.
+
+### Screenshots
+
+![before](/uploads/before.png)
+
+## Notes
+Nothing.";
+        let sections = vec!["checklist".to_string(), "Screenshots".to_string()];
+        assert_eq!(strip_description(description, &sections, false),
+                   "Fixes #12.
+
+## Details
+
+Moves the parser.
+
+## Notes
+Nothing.");
+        assert_eq!(strip_description(description, &["details".to_string()], false),
+                   "Fixes #12.
+
+## Checklist
+
+- [x] Tests added
+
+## Notes
+Nothing.");
+    }
+
+    #[test]
+    fn strip_description_task_lists() {
+        let description = "Summary
+
+- [ ] Update docs
+- [x] Add tests
+* [X] Changelog
+- Plain item
+  - [ ] Nested task";
+        assert_eq!(strip_description(description, &[], true),
+                   "Summary
+
+- Plain item");
+        assert_eq!(strip_description(description, &[], false), description);
+    }
+
+    #[test]
+    fn strip_description_code_blocks() {
+        let description = "Usage:
+
+```sh
+# Checklist
+
+- [ ] not a task
+
+make
+```
+
+## Checklist
+
+- [ ] Done";
+        assert_eq!(strip_description(description, &["checklist".to_string()], true),
+                   "Usage:
+
+```sh
+# Checklist
+
+- [ ] not a task
+
+make
+```
+");
+    }
+
+    #[test]
+    fn normalize_message_blank_lines() {
+        assert_eq!(normalize_message("\n\nMerge branch 'a'  \n\nBody\t\n\n\n"),
+                   "Merge branch 'a'\n\nBody");
+        assert_eq!(normalize_message("Title\n\n```\nfoo\n\n\nbar\n```\n"),
+                   "Title\n\n```\nfoo\n\n\nbar\n```");
+        assert_eq!(normalize_message("a\r\n\r\nb"), "a\n\nb");
+        assert_eq!(normalize_message("\n  \n"), "");
+    }
+}
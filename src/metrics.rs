@@ -0,0 +1,178 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::{Arc, Mutex};
+
+// Upper bounds (in seconds) of the merge duration histogram buckets
+const MERGE_DURATION_BUCKETS: &'static [u64] = &[300, 900, 1800, 3600, 7200, 14400, 28800, 86400,
+                                                 172800, 604800];
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd)]
+pub enum Counter {
+    Merged,
+    TestFailed,
+    Conflicted,
+    PushFailed,
+    Errored,
+}
+
+impl Counter {
+    fn all() -> &'static [Counter] {
+        const ALL: &'static [Counter] = &[Counter::Merged,
+                                          Counter::TestFailed,
+                                          Counter::Conflicted,
+                                          Counter::PushFailed,
+                                          Counter::Errored];
+        ALL
+    }
+
+    fn name(&self) -> &'static str {
+        match *self {
+            Counter::Merged => "jaba_merged_total",
+            Counter::TestFailed => "jaba_test_failed_total",
+            Counter::Conflicted => "jaba_conflicted_total",
+            Counter::PushFailed => "jaba_push_failed_total",
+            Counter::Errored => "jaba_errored_total",
+        }
+    }
+
+    fn help(&self) -> &'static str {
+        match *self {
+            Counter::Merged => "Merge requests merged into the target branch",
+            Counter::TestFailed => "Tests of merge requests failed",
+            Counter::Conflicted => "Merge requests conflicted with the target branch",
+            Counter::PushFailed => "Failures of pushing tested merge commits",
+            Counter::Errored => "Merge request evaluations failed with errors",
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Histogram {
+    buckets: Vec<u64>,
+    sum: u64,
+    count: u64,
+}
+
+impl Histogram {
+    fn observe(&mut self, value: u64) {
+        if self.buckets.is_empty() {
+            self.buckets = vec![0; MERGE_DURATION_BUCKETS.len()];
+        }
+        for (bucket, le) in self.buckets.iter_mut().zip(MERGE_DURATION_BUCKETS) {
+            if value <= *le {
+                *bucket += 1;
+            }
+        }
+        self.sum += value;
+        self.count += 1;
+    }
+}
+
+#[derive(Debug, Default)]
+struct Registry {
+    queue_size: BTreeMap<(String, String, &'static str), usize>,
+    counters: BTreeMap<(Counter, String), u64>,
+    merge_duration: BTreeMap<String, Histogram>,
+}
+
+/// Metrics of queues and merge activities exported in the Prometheus text format
+#[derive(Debug, Clone, Default)]
+pub struct Metrics {
+    registry: Arc<Mutex<Registry>>,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn set_queue_size(&self,
+                          repo: &str,
+                          target_branch: &str,
+                          bucket: &'static str,
+                          size: usize) {
+        let mut registry = self.registry.lock().unwrap();
+        let _ = registry.queue_size.insert((repo.into(), target_branch.into(), bucket), size);
+    }
+
+    /// Resets the queue sizes of the repository's target branches (`target_branch` only, or all of
+    /// them if `None`) to zero, so that queues emptied since the last evaluation are reported.
+    pub fn reset_queue_sizes(&self, repo: &str, target_branch: Option<&str>) {
+        let mut registry = self.registry.lock().unwrap();
+        for (&(ref r, ref b, _), size) in &mut registry.queue_size {
+            if r == repo && target_branch.map_or(true, |t| t == b) {
+                *size = 0;
+            }
+        }
+    }
+
+    pub fn increment(&self, counter: Counter, repo: &str) {
+        let mut registry = self.registry.lock().unwrap();
+        *registry.counters.entry((counter, repo.into())).or_insert(0) += 1;
+    }
+
+    /// Records the time from the approval to the merge in seconds.
+    pub fn observe_merge_duration(&self, repo: &str, seconds: u64) {
+        let mut registry = self.registry.lock().unwrap();
+        registry.merge_duration
+            .entry(repo.into())
+            .or_insert_with(Histogram::default)
+            .observe(seconds);
+    }
+
+    pub fn render(&self) -> String {
+        let registry = self.registry.lock().unwrap();
+        let mut out = String::new();
+
+        out.push_str("# HELP jaba_queue_size Merge requests in each queue bucket\n");
+        out.push_str("# TYPE jaba_queue_size gauge\n");
+        for (&(ref repo, ref target_branch, bucket), size) in &registry.queue_size {
+            let _ = writeln!(out,
+                             "jaba_queue_size{{repo=\"{}\",target_branch=\"{}\",bucket=\"{}\"}} {}",
+                             escape(repo),
+                             escape(target_branch),
+                             bucket,
+                             size);
+        }
+
+        for counter in Counter::all() {
+            let _ = writeln!(out, "# HELP {} {}", counter.name(), counter.help());
+            let _ = writeln!(out, "# TYPE {} counter", counter.name());
+            for (&(_, ref repo), value) in
+                registry.counters.iter().filter(|&(&(c, _), _)| c == *counter) {
+                let _ = writeln!(out, "{}{{repo=\"{}\"}} {}", counter.name(), escape(repo), value);
+            }
+        }
+
+        out.push_str("# HELP jaba_merge_duration_seconds Time from approval to merge\n");
+        out.push_str("# TYPE jaba_merge_duration_seconds histogram\n");
+        for (repo, histogram) in &registry.merge_duration {
+            let repo = escape(repo);
+            for (bucket, le) in histogram.buckets.iter().zip(MERGE_DURATION_BUCKETS) {
+                let _ = writeln!(out,
+                                 "jaba_merge_duration_seconds_bucket{{repo=\"{}\",le=\"{}\"}} {}",
+                                 repo,
+                                 le,
+                                 bucket);
+            }
+            let _ = writeln!(out,
+                             "jaba_merge_duration_seconds_bucket{{repo=\"{}\",le=\"+Inf\"}} {}",
+                             repo,
+                             histogram.count);
+            let _ = writeln!(out,
+                             "jaba_merge_duration_seconds_sum{{repo=\"{}\"}} {}",
+                             repo,
+                             histogram.sum);
+            let _ = writeln!(out,
+                             "jaba_merge_duration_seconds_count{{repo=\"{}\"}} {}",
+                             repo,
+                             histogram.count);
+        }
+
+        out
+    }
+}
+
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
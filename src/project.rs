@@ -1,21 +1,39 @@
-use config::{Git as GitConfig, Repo as RepoConfig};
+use audit::AuditLog;
+use config::{Audit as AuditConfig, Git as GitConfig, GitAuth, Repo as RepoConfig,
+             Store as StoreConfig};
 use errors::*;
-use git2::{Branch, BranchType, Commit, Cred, FetchOptions, FetchPrune, ObjectType, PushOptions,
-           RemoteCallbacks, Repository, ResetType};
-use gitlab::{self, AccessLevel, Member, MergeRequestStateFilter, NamespaceId, ObjectId, UserId};
+use git2::{self, Cert, Commit, Cred, FetchOptions, PushOptions, Remote, RemoteCallbacks,
+           Repository};
+use gitlab::{self, AccessLevel, GroupId, Member, MergeRequestStateFilter, NamespaceId, ObjectId,
+             ProjectId, UserBasic};
 use gitlab_ext::GitlabExt;
-use merge_request::MergeRequest;
+use host_key;
+use merge_request::{MERGE_BRANCH_PREFIX, MergeRequest, TRY_BRANCH_PREFIX};
+use metrics::Metrics;
+use store::Store;
 use slog::Logger;
+use std::cell::{Cell, RefCell};
+use std::collections::BTreeSet;
+use std::fs::File;
+use std::io::prelude::*;
 use std::path::{Path, PathBuf};
 
+const MERGE_REQUEST_REF_PREFIX: &'static str = "refs/jaba/mr/";
+const LOCAL_BRANCH_REF_PREFIX: &'static str = "refs/jaba/heads/";
+
 pub struct Project<'a> {
     log: Logger,
+    label: String,
     gitlab: &'a GitlabExt,
     project: gitlab::Project,
     repository: Repository,
-    _repo_config: &'a RepoConfig,
+    repo_config: &'a RepoConfig,
     git_config: &'a GitConfig,
     members: Vec<Member>,
+    reviewer_group_members: Vec<Member>,
+    metrics: Metrics,
+    store: Option<Store>,
+    audit: Option<AuditLog>,
 }
 
 impl<'a> Project<'a> {
@@ -23,39 +41,102 @@ impl<'a> Project<'a> {
                label: &str,
                repo_config: &'a RepoConfig,
                git_config: &'a GitConfig,
-               gitlab: &'a GitlabExt)
+               gitlab: &'a GitlabExt,
+               metrics: &Metrics,
+               store_config: Option<&StoreConfig>,
+               audit_config: Option<&AuditConfig>)
                -> Result<Self> {
         let log = log.new(o!("project" => label.to_string()));
 
         let project = gitlab.gitlab().project_by_name(&repo_config.name)?;
         let repository = open_repository(&project, git_config)?;
 
-        let mut members = gitlab.gitlab().project_members(project.id)?;
-        if let NamespaceId::Group(groupid) = project.namespace.owner_id() {
-            members.extend(gitlab.gitlab().group_members(groupid)?);
-        }
+        let members = project_members(gitlab, &project)?;
+        let reviewer_group_members = reviewer_group_members(gitlab, repo_config)?;
+
+        let store = match store_config {
+            Some(store_config) => Some(Store::open(&log, &store_config.path)?),
+            None => None,
+        };
+        let audit = match audit_config {
+            Some(audit_config) => Some(AuditLog::new(&audit_config.path)?),
+            None => None,
+        };
 
         info!(log, "start project";
               "id" => project.id.value(),
               "path" => project.path_with_namespace);
 
-        let project = Project {
+        Ok(Project {
             log: log,
+            label: label.into(),
             gitlab: gitlab,
             project: project,
             repository: repository,
-            _repo_config: repo_config,
+            repo_config: repo_config,
             git_config: git_config,
             members: members,
-        };
+            reviewer_group_members: reviewer_group_members,
+            metrics: metrics.clone(),
+            store: store,
+            audit: audit,
+        })
+    }
 
-        Ok(project)
+    /// Reports misconfigured permissions up front rather than after a full test run.
+    pub fn preflight(&self) {
+        match permission_problems(self.gitlab, &self.project, &self.members) {
+            Ok(problems) => {
+                for problem in problems {
+                    warn!(self.log, "insufficient permission";
+                          "branch" => problem.branch,
+                          "problem" => problem.message);
+                }
+            }
+            Err(e) => {
+                warn!(self.log, "failed to check permissions");
+                super::dump_error(&self.log, &e);
+            }
+        }
+    }
+
+    /// Reloads the project members and the reviewer group members.
+    pub fn refresh_members(&mut self) -> Result<()> {
+        let members = project_members(self.gitlab, &self.project)?;
+        let reviewer_group_members = reviewer_group_members(self.gitlab, self.repo_config)?;
+        debug!(self.log, "members refreshed";
+               "members" => members.len(),
+               "reviewer_group_members" => reviewer_group_members.len());
+
+        self.members = members;
+        self.reviewer_group_members = reviewer_group_members;
+        Ok(())
     }
 
     pub fn log(&self) -> &Logger {
         &self.log
     }
 
+    pub fn label(&self) -> &str {
+        &self.label
+    }
+
+    pub fn metrics(&self) -> &Metrics {
+        &self.metrics
+    }
+
+    pub fn store(&self) -> Option<&Store> {
+        self.store.as_ref()
+    }
+
+    pub fn audit(&self) -> Option<&AuditLog> {
+        self.audit.as_ref()
+    }
+
+    pub fn repo_config(&self) -> &RepoConfig {
+        self.repo_config
+    }
+
     pub fn gitlab(&self) -> &GitlabExt {
         self.gitlab
     }
@@ -68,64 +149,186 @@ impl<'a> Project<'a> {
         &self.repository
     }
 
+    /// Fetches the branch of the remote into its remote-tracking branch.
     pub fn repository_fetch_branch(&'a self,
                                    remote_name: &str,
                                    branch_name: &str)
                                    -> Result<BranchInfo<'a>> {
         let mut remote = self.repository.find_remote(remote_name)?;
+        let refname = format!("refs/remotes/{}/{}", remote_name, branch_name);
+        self.fetch(&mut remote, branch_name, refname)
+    }
+
+    /// Fetches the source branch of the merge request into `refs/jaba/mr/<iid>`.
+    ///
+    /// Source branches are fetched through anonymous remotes, so that the configured remotes are
+    /// never rewritten.
+    pub fn repository_fetch_merge_request(&'a self,
+                                          source_project: &gitlab::Project,
+                                          mr: &gitlab::MergeRequest)
+                                          -> Result<BranchInfo<'a>> {
+        let mut remote = self.repository.remote_anonymous(self.repository_url(source_project))?;
+        let refname = format!("{}{}", MERGE_REQUEST_REF_PREFIX, mr.iid.value());
+        self.fetch(&mut remote, &mr.source_branch, refname)
+    }
+
+    /// Points the local ref of the merge or try branch at the commit and returns its name.
+    ///
+    /// Local refs are only used as sources of pushes. Nothing is checked out.
+    pub fn repository_update_local_branch(&self,
+                                          branch_name: &str,
+                                          commit: &Commit)
+                                          -> Result<String> {
+        let refname = format!("{}{}", LOCAL_BRANCH_REF_PREFIX, branch_name);
+        let _ = self.repository.reference(&refname, commit.id(), true, "jaba: update")?;
+        Ok(refname)
+    }
+
+    fn fetch(&'a self,
+             remote: &mut Remote,
+             branch_name: &str,
+             refname: String)
+             -> Result<BranchInfo<'a>> {
+        let host_key_error = RefCell::new(None);
+        let port = remote.url().map_or(host_key::DEFAULT_SSH_PORT, host_key::ssh_port);
+        let attempts = Cell::new(0);
         let mut cb = RemoteCallbacks::new();
-        let _ = cb.credentials(|_, _, _| {
-                Cred::ssh_key("git", None, Path::new(&self.git_config.ssh_key), None)
-            })
+        let _ = cb.credentials(|_, username, _| self.credentials(username, &attempts))
             .sideband_progress(|data| {
                 debug!(self.log, "fetch: receive progress";
                        "data" => String::from_utf8_lossy(data).to_string());
                 true
             });
+        if !self.git_config.auth.is_https() {
+            let _ = cb.certificate_check(|cert, hostname| {
+                self.check_host_key(cert, hostname, port, &host_key_error)
+            });
+        }
 
         let mut fo = FetchOptions::new();
-        let _ = fo.remote_callbacks(cb)
-            .prune(FetchPrune::On);
+        let _ = fo.remote_callbacks(cb);
 
-        remote.fetch(&[branch_name], Some(&mut fo), None)?;
+        let refspec = format!("+refs/heads/{}:{}", branch_name, refname);
+        if let Err(e) = remote.fetch(&[refspec.as_str()], Some(&mut fo), None) {
+            return Err(host_key_error.borrow_mut().take().unwrap_or_else(|| e.into()));
+        }
 
-        let branch = self.repository
-            .find_branch(&format!("{}/{}", remote_name, branch_name),
-                         BranchType::Remote)?;
-        let commit = self.repository.find_commit(branch.get().target().unwrap())?;
+        let commit = self.repository.find_commit(self.repository.refname_to_id(&refname)?)?;
 
         Ok(BranchInfo {
-            branch: branch,
+            refname: refname,
             commit: commit,
         })
     }
 
+    /// Pushes the refspec to the remote.
+    ///
+    /// Refs rejected by the remote are reported as `ErrorKind::PushRejected`, since `git2` reports
+    /// them only through the callback.
     pub fn repository_push_branch(&self, remote_name: &str, refspec: &str) -> Result<()> {
         let mut remote = self.repository.find_remote(remote_name)?;
-        let mut cb = RemoteCallbacks::new();
-        let _ = cb.credentials(|_, _, _| {
-                Cred::ssh_key("git", None, Path::new(&self.git_config.ssh_key), None)
-            })
-            .sideband_progress(|data| {
-                debug!(self.log, "push: receive progress";
-                       "data" => String::from_utf8_lossy(data).to_string());
-                true
-            });
+        let port = remote.url().map_or(host_key::DEFAULT_SSH_PORT, host_key::ssh_port);
+        let host_key_error = RefCell::new(None);
+        let attempts = Cell::new(0);
+        let remote_messages = RefCell::new(String::new());
+        let rejected = RefCell::new(None);
+        {
+            let mut cb = RemoteCallbacks::new();
+            let _ = cb.credentials(|_, username, _| self.credentials(username, &attempts))
+                .sideband_progress(|data| {
+                    let data = String::from_utf8_lossy(data);
+                    debug!(self.log, "push: receive progress"; "data" => data.to_string());
+                    remote_messages.borrow_mut().push_str(&data);
+                    true
+                })
+                .push_update_reference(|refname, status| {
+                    if let Some(status) = status {
+                        warn!(self.log, "push: rejected"; "ref" => refname, "status" => status);
+                        *rejected.borrow_mut() = Some((refname.to_string(), status.to_string()));
+                    }
+                    Ok(())
+                });
+            if !self.git_config.auth.is_https() {
+                let _ = cb.certificate_check(|cert, hostname| {
+                    self.check_host_key(cert, hostname, port, &host_key_error)
+                });
+            }
+
+            let mut po = PushOptions::new();
+            let _ = po.remote_callbacks(cb);
 
-        let mut po = PushOptions::new();
-        let _ = po.remote_callbacks(cb);
+            if let Err(e) = remote.push(&[refspec], Some(&mut po)) {
+                return Err(host_key_error.borrow_mut().take().unwrap_or_else(|| e.into()));
+            }
+        }
 
-        remote.push(&[refspec], Some(&mut po))?;
+        if let Some((refname, status)) = rejected.into_inner() {
+            let remote_messages = remote_messages.into_inner();
+            let reason = PushRejection::new(&status, &remote_messages);
+            let message = if remote_messages.trim().is_empty() {
+                status
+            } else {
+                format!("{} ({})", status, remote_messages.trim())
+            };
+            bail!(ErrorKind::PushRejected(refname, reason, message));
+        }
 
         Ok(())
     }
 
-    pub fn repository_reset_branch(&self, branch: &Branch) -> Result<()> {
-        let refname = branch.get().name().unwrap();
-        self.repository.set_head(refname)?;
-        self.repository
-            .reset(&branch.get().peel(ObjectType::Any)?, ResetType::Hard, None)?;
-        Ok(())
+    /// Returns the URL of the project's repository accessible with the configured authentication.
+    pub fn repository_url<'p>(&self, project: &'p gitlab::Project) -> &'p str {
+        repository_url(project, self.git_config)
+    }
+
+    // Host key callback shared by fetch and push (HTTPS certificates are verified by libgit2).
+    // The failure is kept since the error returned from `git2` does not tell the reason.
+    fn check_host_key(&self,
+                      cert: &Cert,
+                      hostname: &str,
+                      port: u16,
+                      error: &RefCell<Option<Error>>)
+                      -> bool {
+        let result = host_key::verify(&self.git_config.host_key_check, cert, hostname, port)
+            .chain_err(|| ErrorKind::HostKeyVerification(hostname.into()));
+        match result {
+            Ok(()) => true,
+            Err(e) => {
+                warn!(self.log, "host key verification failed"; "host" => hostname);
+                *error.borrow_mut() = Some(e);
+                false
+            }
+        }
+    }
+
+    // Credential callback shared by fetch and push. libgit2 calls the callback again as long as
+    // the credentials are rejected, so the second attempt fails
+    fn credentials(&self,
+                   username: Option<&str>,
+                   attempts: &Cell<u32>)
+                   -> ::std::result::Result<Cred, git2::Error> {
+        attempts.set(attempts.get() + 1);
+        if attempts.get() > 1 {
+            return Err(git2::Error::from_str("authentication failed"));
+        }
+
+        let username = username.unwrap_or("git");
+        match self.git_config.auth {
+            GitAuth::SshKey { ref path, ref passphrase_file } => {
+                let passphrase = match *passphrase_file {
+                    Some(ref passphrase_file) => Some(read_passphrase(passphrase_file)?),
+                    None => None,
+                };
+                Cred::ssh_key(username,
+                              None,
+                              path,
+                              passphrase.as_ref().map(|s| s.as_str()))
+            }
+            GitAuth::SshAgent => Cred::ssh_key_from_agent(username),
+            GitAuth::HttpsToken { ref token } => {
+                Cred::userpass_plaintext(&self.gitlab.current_user().username, token)
+            }
+        }
     }
 
     pub fn opened_merge_requests(&'a self) -> Result<impl Iterator<Item = MergeRequest<'a>> + 'a> {
@@ -136,17 +339,189 @@ impl<'a> Project<'a> {
             .map(move |mr| MergeRequest::from_gitlab_mr(self, mr)))
     }
 
-    pub fn is_reviewer(&self, id: UserId) -> bool {
-        self.members
+    /// Returns whether any opened merge request comes from the source project.
+    pub fn has_merge_request_from(&self, source_project_id: ProjectId) -> Result<bool> {
+        Ok(self.gitlab
+            .gitlab()
+            .merge_requests_with_state(self.project.id, MergeRequestStateFilter::Opened)?
             .iter()
-            .find(|member| member.id == id)
-            .map_or(false,
-                    |member| member.access_level >= AccessLevel::Master.into())
+            .any(|mr| mr.source_project_id == source_project_id))
+    }
+
+    pub fn is_reviewer(&self, user: &UserBasic) -> bool {
+        if self.repo_config.reviewers.iter().any(|name| *name == user.username) {
+            return true;
+        }
+
+        if self.reviewer_group_members.iter().any(|member| member.id == user.id) {
+            return true;
+        }
+
+        self.repo_config.reviewer_min_access_level.map_or(false, |level| {
+            let level: u64 = level.into();
+            self.members
+                .iter()
+                .any(|member| member.id == user.id && member.access_level >= level)
+        })
+    }
+}
+
+/// Checks the permissions of the bot user on the repository without opening its git repository.
+pub fn check_permissions(gitlab: &GitlabExt,
+                         repo_config: &RepoConfig)
+                         -> Result<Vec<PermissionProblem>> {
+    let project = gitlab.gitlab().project_by_name(&repo_config.name)?;
+    let members = project_members(gitlab, &project)?;
+    permission_problems(gitlab, &project, &members)
+}
+
+// Checks whether the bot user can push to the target branches of the opened merge requests (and
+// the default branch) and to the merge branches and try branches created for them
+fn permission_problems(gitlab: &GitlabExt,
+                       project: &gitlab::Project,
+                       members: &[Member])
+                       -> Result<Vec<PermissionProblem>> {
+    let me = gitlab.current_user();
+    if me.is_admin == Some(true) {
+        return Ok(vec![]);
+    }
+
+    let access_level = members.iter()
+        .filter(|member| member.id == me.id)
+        .map(|member| member.access_level)
+        .max()
+        .unwrap_or(0);
+    let developer: u64 = AccessLevel::Developer.into();
+    let master: u64 = AccessLevel::Master.into();
+
+    let protected_branches = gitlab.protected_branches(project.id)?;
+
+    let mut target_branches = gitlab.gitlab()
+        .merge_requests_with_state(project.id, MergeRequestStateFilter::Opened)?
+        .into_iter()
+        .map(|mr| mr.target_branch)
+        .collect::<BTreeSet<_>>();
+    if let Some(ref default_branch) = project.default_branch {
+        let _ = target_branches.insert(default_branch.clone());
+    }
+
+    let mut problems = vec![];
+    for target_branch in &target_branches {
+        let names = [target_branch.clone(),
+                     format!("{}{}", MERGE_BRANCH_PREFIX, target_branch),
+                     format!("{}{}", TRY_BRANCH_PREFIX, target_branch)];
+        for name in &names {
+            // Branches not created yet are protected by the matching wildcard rules as well
+            let rules = protected_branches.iter()
+                .filter(|rule| matches_wildcard(&rule.name, name))
+                .collect::<Vec<_>>();
+            let message = if rules.is_empty() {
+                if access_level >= developer {
+                    continue;
+                }
+                "cannot push to the branch (developer access required)".into()
+            } else {
+                // Rules without push access levels allow masters only
+                let is_allowed = rules.iter().any(|rule| if rule.push_access_levels.is_empty() {
+                    access_level >= master
+                } else {
+                    rule.push_access_levels
+                        .iter()
+                        .any(|level| level.access_level > 0 && access_level >= level.access_level)
+                });
+                if is_allowed {
+                    continue;
+                }
+                let patterns = rules.iter().map(|rule| rule.name.as_str()).collect::<Vec<_>>();
+                format!("cannot push to the branch protected by `{}`", patterns.join("`, `"))
+            };
+            problems.push(PermissionProblem {
+                branch: name.clone(),
+                message: message,
+            });
+        }
+    }
+
+    Ok(problems)
+}
+
+// Protected branch names may contain `*` matching any string
+fn matches_wildcard(pattern: &str, name: &str) -> bool {
+    let mut parts = pattern.split('*').collect::<Vec<_>>();
+    let first = parts.remove(0);
+    if !name.starts_with(first) {
+        return false;
+    }
+    let mut rest = &name[first.len()..];
+
+    let last = match parts.pop() {
+        Some(last) => last,
+        None => return rest.is_empty(),
+    };
+    for part in parts {
+        match rest.find(part) {
+            Some(pos) => rest = &rest[pos + part.len()..],
+            None => return false,
+        }
+    }
+    rest.ends_with(last)
+}
+
+/// Branch the bot user is not permitted to push to
+#[derive(Debug, Clone)]
+pub struct PermissionProblem {
+    pub branch: String,
+    pub message: String,
+}
+
+/// Reason why the remote rejected the pushed ref
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum PushRejection {
+    /// Remote ref has been updated since fetched
+    NonFastForward,
+    /// Protected branch or insufficient permission of the user
+    PermissionDenied,
+    /// Declined by server-side hooks
+    HookDeclined,
+    Other,
+}
+
+impl PushRejection {
+    /// Classifies the rejection by the ref status and the messages sent from the remote.
+    ///
+    /// GitLab reports pushes to protected branches as hook declines, so the remote messages are
+    /// checked before the status.
+    fn new(status: &str, remote_messages: &str) -> Self {
+        let status = status.to_lowercase();
+        let remote_messages = remote_messages.to_lowercase();
+
+        if status.contains("non-fast-forward") || status.contains("fetch first") ||
+           status.contains("stale info") {
+            return PushRejection::NonFastForward;
+        }
+        if ["protected", "not allowed", "permission", "denied"]
+            .iter()
+            .any(|word| status.contains(word) || remote_messages.contains(word)) {
+            return PushRejection::PermissionDenied;
+        }
+        if status.contains("hook") {
+            return PushRejection::HookDeclined;
+        }
+        PushRejection::Other
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            PushRejection::NonFastForward => "non-fast-forward",
+            PushRejection::PermissionDenied => "permission denied",
+            PushRejection::HookDeclined => "hook declined",
+            PushRejection::Other => "rejected",
+        }
     }
 }
 
 pub struct BranchInfo<'repo> {
-    pub branch: Branch<'repo>,
+    pub refname: String,
     pub commit: Commit<'repo>,
 }
 
@@ -156,18 +531,127 @@ impl<'repo> BranchInfo<'repo> {
     }
 }
 
+// Members of the project including members inherited from its group
+fn project_members(gitlab: &GitlabExt, project: &gitlab::Project) -> Result<Vec<Member>> {
+    let mut members = gitlab.gitlab().project_members(project.id)?;
+    if let NamespaceId::Group(groupid) = project.namespace.owner_id() {
+        members.extend(gitlab.inherited_group_members(groupid)?);
+    }
+    Ok(members)
+}
+
+fn reviewer_group_members(gitlab: &GitlabExt, repo_config: &RepoConfig) -> Result<Vec<Member>> {
+    let mut members = vec![];
+    for path in &repo_config.reviewer_groups {
+        let group = gitlab.group_by_path(path)
+            .chain_err(|| format!("failed to get reviewer group: {}", path))?;
+        members.extend(gitlab.inherited_group_members(GroupId::new(group.id))?);
+    }
+    Ok(members)
+}
+
 fn open_repository(project: &gitlab::Project, git_config: &GitConfig) -> Result<Repository> {
     let mut path = PathBuf::from(&git_config.cache_directory);
     path.push(&project.path_with_namespace);
 
+    // Merges are done in memory, so the repository has no working directory
     let repo = if !path.exists() {
-        let repo = Repository::init(&path)?;
-        let _ = repo.remote("origin", &project.ssh_url_to_repo)?;
-        let _ = repo.remote("mr", "https://example.com/")?; // Dummy URL
+        let repo = Repository::init_bare(&path)?;
+        let _ = repo.remote("origin", repository_url(project, git_config))?;
         repo
     } else {
-        Repository::open(&path)?
+        // Authentication may have been changed since the repository was cloned
+        let repo = Repository::open(&path)?;
+        repo.remote_set_url("origin", repository_url(project, git_config))?;
+        repo
     };
 
     Ok(repo)
 }
+
+fn repository_url<'p>(project: &'p gitlab::Project, git_config: &GitConfig) -> &'p str {
+    if git_config.auth.is_https() {
+        &project.http_url_to_repo
+    } else {
+        &project.ssh_url_to_repo
+    }
+}
+
+fn read_passphrase(path: &Path) -> ::std::result::Result<String, git2::Error> {
+    let mut passphrase = String::new();
+    let result = File::open(path).and_then(|mut file| file.read_to_string(&mut passphrase));
+    if let Err(e) = result {
+        return Err(git2::Error::from_str(&format!("failed to read passphrase file: {}: {}",
+                                                  path.to_string_lossy(),
+                                                  e)));
+    }
+    Ok(passphrase.trim_right_matches(&['\r', '\n'][..]).to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_protected_branch_patterns() {
+        assert!(matches_wildcard("auto-*", "auto-master"));
+        assert!(matches_wildcard("auto-*", "auto-"));
+        assert!(!matches_wildcard("auto-*", "master"));
+        assert!(!matches_wildcard("auto-*", "try-master"));
+
+        assert!(matches_wildcard("*", "master"));
+        assert!(matches_wildcard("*", "auto-master"));
+
+        assert!(matches_wildcard("*-stable", "9-0-stable"));
+        assert!(matches_wildcard("*-stable", "auto-9-0-stable"));
+        assert!(!matches_wildcard("*-stable", "9-0-stable-fix"));
+
+        assert!(matches_wildcard("master", "master"));
+        assert!(!matches_wildcard("master", "auto-master"));
+        assert!(!matches_wildcard("master", "master2"));
+        assert!(!matches_wildcard("Master", "master"));
+
+        assert!(matches_wildcard("release/*/rc*", "release/1.0/rc2"));
+        assert!(!matches_wildcard("a*a", "a"));
+    }
+
+    #[test]
+    fn push_rejection_protected_branch() {
+        let messages = "GitLab: You are not allowed to push code to protected branches on this \
+                        project.\n";
+        assert_eq!(PushRejection::new("pre-receive hook declined", messages),
+                   PushRejection::PermissionDenied);
+        let messages = "GitLab: You are not allowed to force push code to a protected branch on \
+                        this project.\n";
+        assert_eq!(PushRejection::new("pre-receive hook declined", messages),
+                   PushRejection::PermissionDenied);
+    }
+
+    #[test]
+    fn push_rejection_hook_declined() {
+        let messages = "GL-HOOK-ERR: Commit message must reference a JIRA issue\n";
+        assert_eq!(PushRejection::new("pre-receive hook declined", messages),
+                   PushRejection::HookDeclined);
+        assert_eq!(PushRejection::new("pre-receive hook declined", ""),
+                   PushRejection::HookDeclined);
+        assert_eq!(PushRejection::new("update hook declined", ""),
+                   PushRejection::HookDeclined);
+    }
+
+    #[test]
+    fn push_rejection_non_fast_forward() {
+        assert_eq!(PushRejection::new("non-fast-forward", ""),
+                   PushRejection::NonFastForward);
+        assert_eq!(PushRejection::new("fetch first", ""), PushRejection::NonFastForward);
+        assert_eq!(PushRejection::new("stale info", ""), PushRejection::NonFastForward);
+        // Preceded by the remote ref check even if hooks print messages
+        assert_eq!(PushRejection::new("non-fast-forward", "remote: protected branch\n"),
+                   PushRejection::NonFastForward);
+    }
+
+    #[test]
+    fn push_rejection_other() {
+        assert_eq!(PushRejection::new("failed to update ref", ""), PushRejection::Other);
+        assert_eq!(PushRejection::new("funny refname", ""), PushRejection::Other);
+    }
+}
@@ -0,0 +1,170 @@
+use chan::Sender;
+use config::Server as ServerConfig;
+use errors::*;
+use hyper::header::ContentType;
+use hyper::method::Method;
+use hyper::net::Fresh;
+use hyper::server::{Handler, Listening, Request, Response, Server};
+use hyper::status::StatusCode;
+use hyper::uri::RequestUri;
+use metrics::Metrics;
+use serde_json;
+use slog::Logger;
+use status::{self, StatusBoard};
+use std::io::prelude::*;
+use url::percent_encoding::percent_decode;
+use webhook::{self, Trigger};
+
+const WEBHOOK_PATH: &'static str = "/webhook";
+const METRICS_PATH: &'static str = "/metrics";
+const QUEUE_API_PREFIX: &'static str = "/api/queue/";
+const QUEUE_API_SUFFIX: &'static str = ".json";
+
+pub fn start(log: &Logger,
+             config: &ServerConfig,
+             hook: Sender<Trigger>,
+             status: StatusBoard,
+             metrics: Metrics)
+             -> Result<Listening> {
+    let log = log.new(o!("scope" => "server"));
+
+    let handler = ServerHandler {
+        log: log.clone(),
+        webhook_token: config.webhook_token.clone(),
+        hook: hook,
+        status: status,
+        metrics: metrics,
+    };
+    let listening = Server::http(config.listen.as_str())?.handle(handler)?;
+    info!(log, "start server"; "listen" => config.listen);
+
+    Ok(listening)
+}
+
+#[derive(Debug)]
+struct ServerHandler {
+    log: Logger,
+    webhook_token: Option<String>,
+    hook: Sender<Trigger>,
+    status: StatusBoard,
+    metrics: Metrics,
+}
+
+impl ServerHandler {
+    fn handle_webhook(&self, req: &mut Request) -> (StatusCode, String) {
+        let token = match self.webhook_token {
+            Some(ref token) => token,
+            None => return (StatusCode::NotFound, "webhook is not enabled".into()),
+        };
+
+        let is_valid_token = req.headers
+            .get_raw("X-Gitlab-Token")
+            .and_then(|values| values.first())
+            .map_or(false, |value| constant_time_eq(value, token.as_bytes()));
+        if !is_valid_token {
+            warn!(self.log, "webhook: invalid token");
+            return (StatusCode::Forbidden, "invalid token".into());
+        }
+
+        let event = req.headers
+            .get_raw("X-Gitlab-Event")
+            .and_then(|values| values.first())
+            .map(|value| String::from_utf8_lossy(value).into_owned())
+            .unwrap_or_default();
+
+        let mut body = String::new();
+        if let Err(e) = req.read_to_string(&mut body) {
+            warn!(self.log, "webhook: failed to read request body"; "error" => e.to_string());
+            return (StatusCode::BadRequest, "failed to read request body".into());
+        }
+
+        match webhook::parse(&event, &body) {
+            Ok(Some(trigger)) => {
+                info!(self.log, "webhook: received";
+                      "event" => event,
+                      "project" => trigger.project,
+                      "target_branch" => trigger.target_branch);
+                self.hook.send(trigger);
+                (StatusCode::Ok, "accepted".into())
+            }
+            Ok(None) => {
+                debug!(self.log, "webhook: ignored"; "event" => event);
+                (StatusCode::Ok, "ignored".into())
+            }
+            Err(e) => {
+                warn!(self.log, "webhook: invalid payload"; "event" => event);
+                super::dump_error(&self.log, &e);
+                (StatusCode::BadRequest, "invalid payload".into())
+            }
+        }
+    }
+
+    fn handle_queue(&self, path: &str) -> (StatusCode, String) {
+        // `/api/queue/<repo>/<target_branch>.json` (target branch may contain slashes)
+        let queue = path.trim_left_matches(QUEUE_API_PREFIX)
+            .trim_right_matches(QUEUE_API_SUFFIX)
+            .splitn(2, '/')
+            .collect::<Vec<_>>();
+        if queue.len() != 2 {
+            return (StatusCode::NotFound, "not found".into());
+        }
+
+        let repo = percent_decode(queue[0].as_bytes()).decode_utf8_lossy();
+        let target_branch = percent_decode(queue[1].as_bytes()).decode_utf8_lossy();
+        match self.status.queue(&repo, &target_branch) {
+            Some(queue) => {
+                match serde_json::to_string_pretty(&queue) {
+                    Ok(json) => (StatusCode::Ok, json),
+                    Err(e) => {
+                        warn!(self.log, "failed to serialize queue"; "error" => e.to_string());
+                        (StatusCode::InternalServerError, "internal server error".into())
+                    }
+                }
+            }
+            None => (StatusCode::NotFound, "queue not found".into()),
+        }
+    }
+}
+
+impl Handler for ServerHandler {
+    fn handle<'a, 'k>(&'a self, mut req: Request<'a, 'k>, mut res: Response<'a, Fresh>) {
+        let path = match req.uri {
+            RequestUri::AbsolutePath(ref path) => {
+                path.split('?').next().unwrap_or_default().to_string()
+            }
+            _ => String::new(),
+        };
+
+        let (status, body) = match (req.method.clone(), path.as_str()) {
+            (Method::Post, WEBHOOK_PATH) => self.handle_webhook(&mut req),
+            (Method::Get, "/") => {
+                res.headers_mut().set(ContentType::html());
+                (StatusCode::Ok, status::render_html(&self.status.queues()))
+            }
+            (Method::Get, METRICS_PATH) => {
+                res.headers_mut().set_raw("Content-Type",
+                                          vec![b"text/plain; version=0.0.4".to_vec()]);
+                (StatusCode::Ok, self.metrics.render())
+            }
+            (Method::Get, path) if path.starts_with(QUEUE_API_PREFIX) &&
+                                   path.ends_with(QUEUE_API_SUFFIX) => {
+                res.headers_mut().set(ContentType::json());
+                self.handle_queue(path)
+            }
+            _ => (StatusCode::NotFound, "not found".into()),
+        };
+
+        *res.status_mut() = status;
+        if let Err(e) = res.send(body.as_bytes()) {
+            warn!(self.log, "failed to send response"; "error" => e.to_string());
+        }
+    }
+}
+
+// Compared in constant time so that the token cannot be guessed from response times
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
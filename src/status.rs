@@ -0,0 +1,178 @@
+use build_state::ApprovalInfo;
+use chrono::{DateTime, UTC};
+use merge_request::MergeRequest;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::{Arc, Mutex};
+
+/// Snapshot of a merge request in the queue
+#[derive(Debug, Clone, Serialize)]
+pub struct MergeRequestStatus {
+    pub id: u64,
+    pub iid: u64,
+    pub title: String,
+    pub author: String,
+    pub web_url: String,
+    pub state: String,
+    pub approval: Option<ApprovalInfo>,
+    pub build_url: Option<String>,
+}
+
+impl MergeRequestStatus {
+    pub fn new(mr: &MergeRequest) -> Self {
+        let merge_request = mr.merge_request();
+        MergeRequestStatus {
+            id: merge_request.id.value(),
+            iid: merge_request.iid.value(),
+            title: merge_request.title.clone(),
+            author: merge_request.author.username.clone(),
+            web_url: mr.web_url(),
+            state: mr.state().as_str().into(),
+            approval: mr.state().approval().cloned(),
+            build_url: mr.test_info().map(|info| info.build_url.clone()),
+        }
+    }
+}
+
+/// Snapshot of the queue of a target branch
+#[derive(Debug, Clone, Serialize)]
+pub struct QueueStatus {
+    pub repo: String,
+    pub project: String,
+    pub target_branch: String,
+    pub updated_at: DateTime<UTC>,
+    pub errored: Vec<MergeRequestStatus>,
+    pub init: Vec<MergeRequestStatus>,
+    pub approved: Vec<MergeRequestStatus>,
+    pub running: Vec<MergeRequestStatus>,
+    pub success: Vec<MergeRequestStatus>,
+    pub merged: Vec<MergeRequestStatus>,
+    pub failed: Vec<MergeRequestStatus>,
+}
+
+impl QueueStatus {
+    fn buckets(&self) -> [(&'static str, &[MergeRequestStatus]); 7] {
+        [("running", &self.running[..]),
+         ("success", &self.success[..]),
+         ("approved", &self.approved[..]),
+         ("failed", &self.failed[..]),
+         ("errored", &self.errored[..]),
+         ("init", &self.init[..]),
+         ("merged", &self.merged[..])]
+    }
+}
+
+/// Latest queue snapshots shared between the daemon and the HTTP server
+#[derive(Debug, Clone, Default)]
+pub struct StatusBoard {
+    queues: Arc<Mutex<BTreeMap<(String, String), QueueStatus>>>,
+}
+
+impl StatusBoard {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Replaces the snapshots of the repository's target branches evaluated this time
+    /// (`target_branch` only, or all of them if `None`).
+    ///
+    /// Snapshots of the target branches without merge requests are removed.
+    pub fn update(&self, repo: &str, target_branch: Option<&str>, queues: Vec<QueueStatus>) {
+        let mut map = self.queues.lock().unwrap();
+        let removed = map.keys()
+            .filter(|&&(ref r, ref b)| r == repo && target_branch.map_or(true, |t| t == b))
+            .cloned()
+            .collect::<Vec<_>>();
+        for key in removed {
+            let _ = map.remove(&key);
+        }
+        for queue in queues {
+            let _ = map.insert((queue.repo.clone(), queue.target_branch.clone()), queue);
+        }
+    }
+
+    pub fn queues(&self) -> Vec<QueueStatus> {
+        self.queues.lock().unwrap().values().cloned().collect()
+    }
+
+    pub fn queue(&self, repo: &str, target_branch: &str) -> Option<QueueStatus> {
+        self.queues.lock().unwrap().get(&(repo.into(), target_branch.into())).cloned()
+    }
+}
+
+pub fn render_html(queues: &[QueueStatus]) -> String {
+    let mut html = String::new();
+    html.push_str("<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n");
+    html.push_str("<title>jaba queue</title>\n</head>\n<body>\n");
+
+    if queues.is_empty() {
+        html.push_str("<p>No queue evaluated yet.</p>\n");
+    }
+
+    for queue in queues {
+        let _ = write!(html,
+                       "<h2>{} ({}) - {}</h2>\n<p>Updated at {} (<a href=\"/api/queue/{}/{}.json\">\
+                        json</a>)</p>\n",
+                       escape(&queue.repo),
+                       escape(&queue.project),
+                       escape(&queue.target_branch),
+                       queue.updated_at,
+                       escape(&queue.repo),
+                       escape(&queue.target_branch));
+        html.push_str("<table border=\"1\">\n<tr><th>#</th><th>State</th><th>Merge request</th>\
+                       <th>Author</th><th>Approved by</th><th>Priority</th><th>Rollup</th>\
+                       <th>Build</th></tr>\n");
+
+        let mut n = 0;
+        for &(bucket, mrs) in &queue.buckets() {
+            for mr in mrs {
+                n += 1;
+                let _ = write!(html,
+                               "<tr><td>{}</td><td>{}</td><td><a href=\"{}\">!{}</a> {}</td>\
+                                <td>{}</td>",
+                               n,
+                               bucket,
+                               escape(&mr.web_url),
+                               mr.iid,
+                               escape(&mr.title),
+                               escape(&mr.author));
+                match mr.approval {
+                    Some(ref approval) => {
+                        let _ = write!(html,
+                                       "<td>{}</td><td>{}</td><td>{}</td>",
+                                       escape(&approval.username),
+                                       approval.priority,
+                                       approval.rollup.as_str());
+                    }
+                    None => html.push_str("<td></td><td></td><td></td>"),
+                }
+                match mr.build_url {
+                    Some(ref url) => {
+                        let _ = write!(html, "<td><a href=\"{}\">build</a></td>", escape(url));
+                    }
+                    None => html.push_str("<td></td>"),
+                }
+                html.push_str("</tr>\n");
+            }
+        }
+        html.push_str("</table>\n");
+    }
+
+    html.push_str("</body>\n</html>\n");
+    html
+}
+
+fn escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
@@ -0,0 +1,266 @@
+use build_state::{ApprovalInfo, TestInfo};
+use chrono::UTC;
+use errors::*;
+use gitlab::{self, ProjectId};
+use merge_request::State;
+use rusqlite::{Connection, Error as SqliteError};
+use serde_json;
+use slog::Logger;
+use std::fs;
+use std::path::Path;
+
+const SCHEMA: &'static str = "
+CREATE TABLE IF NOT EXISTS merge_requests (
+    id INTEGER PRIMARY KEY,
+    project_id INTEGER NOT NULL,
+    iid INTEGER NOT NULL,
+    target_branch TEXT NOT NULL,
+    sha TEXT NOT NULL,
+    state TEXT NOT NULL,
+    approval TEXT,
+    test TEXT,
+    updated_at TEXT NOT NULL
+);
+CREATE TABLE IF NOT EXISTS state_history (
+    merge_request_id INTEGER NOT NULL,
+    state TEXT NOT NULL,
+    recorded_at TEXT NOT NULL
+);
+CREATE TABLE IF NOT EXISTS approvals (
+    merge_request_id INTEGER NOT NULL,
+    sha TEXT NOT NULL,
+    approval TEXT,
+    recorded_at TEXT NOT NULL
+);
+CREATE TABLE IF NOT EXISTS test_attempts (
+    merge_request_id INTEGER NOT NULL,
+    merge_sha TEXT NOT NULL,
+    result TEXT NOT NULL,
+    info TEXT NOT NULL,
+    started_at TEXT NOT NULL,
+    updated_at TEXT NOT NULL,
+    PRIMARY KEY (merge_request_id, merge_sha)
+);
+CREATE TABLE IF NOT EXISTS merges (
+    merge_request_id INTEGER NOT NULL,
+    merge_sha TEXT NOT NULL,
+    target_branch TEXT NOT NULL,
+    approval TEXT NOT NULL,
+    merged_at TEXT NOT NULL
+);
+CREATE TABLE IF NOT EXISTS commit_statuses (
+    project_id INTEGER NOT NULL,
+    sha TEXT NOT NULL,
+    name TEXT NOT NULL,
+    status TEXT NOT NULL,
+    description TEXT,
+    PRIMARY KEY (project_id, sha, name)
+);
+";
+
+/// Local record of merge request states and their history.
+///
+/// GitLab commit statuses remain the source of truth. The store is used as a history source and
+/// as a cache of the full commit status descriptions written by jaba.
+#[derive(Debug)]
+pub struct Store {
+    log: Logger,
+    conn: Connection,
+}
+
+impl Store {
+    pub fn open(log: &Logger, path: &Path) -> Result<Self> {
+        let log = log.new(o!("scope" => "store"));
+
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let conn = Connection::open(path)?;
+        conn.execute_batch(SCHEMA)?;
+        debug!(log, "store opened"; "path" => path.to_string_lossy().to_string());
+
+        Ok(Store {
+            log: log,
+            conn: conn,
+        })
+    }
+
+    /// Records the current state of the merge request. Returns `false` if the recorded state is
+    /// not changed.
+    pub fn record_state(&self,
+                        mr: &gitlab::MergeRequest,
+                        state: &State,
+                        test: Option<&TestInfo>)
+                        -> Result<bool> {
+        let id = mr.id.value() as i64;
+        let now = UTC::now().to_rfc3339();
+
+        let approval = match state.approval() {
+            Some(approval) => Some(serde_json::to_string(approval)?),
+            None => None,
+        };
+        let test = match test {
+            Some(test) => Some(serde_json::to_string(test)?),
+            None => None,
+        };
+
+        let last = match self.conn
+            .query_row("SELECT sha, state, approval, test FROM merge_requests WHERE id = ?",
+                       &[&id],
+                       |row| {
+                (row.get::<_, String>(0),
+                 row.get::<_, String>(1),
+                 row.get::<_, Option<String>>(2),
+                 row.get::<_, Option<String>>(3))
+            }) {
+            Ok(last) => Some(last),
+            Err(SqliteError::QueryReturnedNoRows) => None,
+            Err(e) => return Err(e.into()),
+        };
+        let last_state = last.as_ref().map(|&(_, ref state, _, _)| state.clone());
+
+        if let Some((sha, last_state, last_approval, last_test)) = last {
+            if sha == *mr.sha.value() && last_state == state.as_str() &&
+               last_approval == approval && last_test == test {
+                return Ok(false);
+            }
+        }
+
+        let _ = self.conn
+            .execute("INSERT OR REPLACE INTO merge_requests
+                      (id, project_id, iid, target_branch, sha, state, approval, test, updated_at)
+                      VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)",
+                     &[&id,
+                       &(mr.target_project_id.value() as i64),
+                       &(mr.iid.value() as i64),
+                       &mr.target_branch,
+                       mr.sha.value(),
+                       &state.as_str(),
+                       &approval,
+                       &test,
+                       &now])?;
+
+        if last_state.as_ref().map(|s| s.as_str()) != Some(state.as_str()) {
+            trace!(self.log, "state recorded";
+                   "merge_request" => mr.iid.value(),
+                   "before" => last_state,
+                   "after" => state.as_str());
+            let _ = self.conn
+                .execute("INSERT INTO state_history (merge_request_id, state, recorded_at)
+                          VALUES (?, ?, ?)",
+                         &[&id, &state.as_str(), &now])?;
+        }
+
+        Ok(true)
+    }
+
+    /// Records the approval (or its revocation if `approval` is `None`).
+    pub fn record_approval(&self,
+                           mr: &gitlab::MergeRequest,
+                           approval: Option<&ApprovalInfo>)
+                           -> Result<()> {
+        let approval = match approval {
+            Some(approval) => Some(serde_json::to_string(approval)?),
+            None => None,
+        };
+        let _ = self.conn
+            .execute("INSERT INTO approvals (merge_request_id, sha, approval, recorded_at)
+                      VALUES (?, ?, ?, ?)",
+                     &[&(mr.id.value() as i64),
+                       mr.sha.value(),
+                       &approval,
+                       &UTC::now().to_rfc3339()])?;
+        Ok(())
+    }
+
+    /// Records the test of the merge commit and its current result if changed.
+    pub fn record_test(&self,
+                       mr: &gitlab::MergeRequest,
+                       result: &str,
+                       info: &TestInfo)
+                       -> Result<()> {
+        let id = mr.id.value() as i64;
+        let now = UTC::now().to_rfc3339();
+        let json = serde_json::to_string(info)?;
+
+        let last = match self.conn
+            .query_row("SELECT result, info FROM test_attempts
+                        WHERE merge_request_id = ? AND merge_sha = ?",
+                       &[&id, info.merge_sha.value()],
+                       |row| (row.get::<_, String>(0), row.get::<_, String>(1))) {
+            Ok(last) => Some(last),
+            Err(SqliteError::QueryReturnedNoRows) => None,
+            Err(e) => return Err(e.into()),
+        };
+
+        match last {
+            Some((ref last_result, ref last_json)) if last_result == result &&
+                                                      *last_json == json => {}
+            Some(_) => {
+                let _ = self.conn
+                    .execute("UPDATE test_attempts SET result = ?, info = ?, updated_at = ?
+                              WHERE merge_request_id = ? AND merge_sha = ?",
+                             &[&result, &json, &now, &id, info.merge_sha.value()])?;
+            }
+            None => {
+                let _ = self.conn
+                    .execute("INSERT INTO test_attempts
+                              (merge_request_id, merge_sha, result, info, started_at, updated_at)
+                              VALUES (?, ?, ?, ?, ?, ?)",
+                             &[&id, info.merge_sha.value(), &result, &json, &now, &now])?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn record_merge(&self,
+                        mr: &gitlab::MergeRequest,
+                        info: &TestInfo,
+                        approval: &ApprovalInfo)
+                        -> Result<()> {
+        let _ = self.conn
+            .execute("INSERT INTO merges
+                      (merge_request_id, merge_sha, target_branch, approval, merged_at)
+                      VALUES (?, ?, ?, ?, ?)",
+                     &[&(mr.id.value() as i64),
+                       info.merge_sha.value(),
+                       &info.target_branch,
+                       &serde_json::to_string(approval)?,
+                       &UTC::now().to_rfc3339()])?;
+        Ok(())
+    }
+
+    /// Records the commit status written by jaba.
+    pub fn record_commit_status(&self,
+                                project_id: ProjectId,
+                                sha: &str,
+                                name: &str,
+                                status: &str,
+                                description: Option<&str>)
+                                -> Result<()> {
+        let _ = self.conn
+            .execute("INSERT OR REPLACE INTO commit_statuses
+                      (project_id, sha, name, status, description)
+                      VALUES (?, ?, ?, ?, ?)",
+                     &[&(project_id.value() as i64), &sha, &name, &status, &description])?;
+        Ok(())
+    }
+
+    /// Returns the description of the commit status last written by jaba, if its status matches.
+    pub fn commit_status_description(&self,
+                                     project_id: ProjectId,
+                                     sha: &str,
+                                     name: &str,
+                                     status: &str)
+                                     -> Result<Option<String>> {
+        match self.conn
+            .query_row("SELECT description FROM commit_statuses
+                        WHERE project_id = ? AND sha = ? AND name = ? AND status = ?",
+                       &[&(project_id.value() as i64), &sha, &name, &status],
+                       |row| row.get::<_, Option<String>>(0)) {
+            Ok(description) => Ok(description),
+            Err(SqliteError::QueryReturnedNoRows) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
@@ -0,0 +1,118 @@
+use errors::*;
+use std::collections::HashMap;
+
+/// Text template with `{name}` placeholders. `{{` and `}}` are literal braces.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Template {
+    pieces: Vec<Piece>,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+enum Piece {
+    Text(String),
+    Placeholder(String),
+}
+
+impl Template {
+    /// Parses the template, failing if it contains placeholders not listed in `keys`.
+    pub fn parse(source: &str, keys: &[&str]) -> Result<Self> {
+        let mut pieces = vec![];
+        let mut text = String::new();
+        let mut chars = source.chars().peekable();
+
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.peek() == Some(&'{') => {
+                    let _ = chars.next();
+                    text.push('{');
+                }
+                '}' if chars.peek() == Some(&'}') => {
+                    let _ = chars.next();
+                    text.push('}');
+                }
+                '{' => {
+                    let mut name = String::new();
+                    loop {
+                        match chars.next() {
+                            Some('}') => break,
+                            Some(c) => name.push(c),
+                            None => bail!("unclosed placeholder: {{{}", name),
+                        }
+                    }
+                    if !keys.contains(&name.as_str()) {
+                        bail!("unknown placeholder: {{{}}} (available: {})",
+                              name,
+                              keys.join(", "));
+                    }
+                    if !text.is_empty() {
+                        pieces.push(Piece::Text(text));
+                        text = String::new();
+                    }
+                    pieces.push(Piece::Placeholder(name));
+                }
+                '}' => bail!("unmatched `}}`"),
+                c => text.push(c),
+            }
+        }
+
+        if !text.is_empty() {
+            pieces.push(Piece::Text(text));
+        }
+
+        Ok(Template { pieces: pieces })
+    }
+
+    /// Renders the template. Placeholders without value are rendered as empty strings.
+    pub fn render(&self, values: &HashMap<&str, String>) -> String {
+        let mut output = String::new();
+        for piece in &self.pieces {
+            match *piece {
+                Piece::Text(ref text) => output.push_str(text),
+                Piece::Placeholder(ref name) => {
+                    if let Some(value) = values.get(name.as_str()) {
+                        output.push_str(value);
+                    }
+                }
+            }
+        }
+        output
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    const KEYS: &'static [&'static str] = &["title", "iid"];
+
+    fn render(source: &str, values: &[(&'static str, &str)]) -> String {
+        let values = values.iter().map(|&(k, v)| (k, v.to_string())).collect::<HashMap<_, _>>();
+        Template::parse(source, KEYS).unwrap().render(&values)
+    }
+
+    #[test]
+    fn render_placeholders() {
+        assert_eq!(render("Merge !{iid}: {title}", &[("iid", "3"), ("title", "Fix")]),
+                   "Merge !3: Fix");
+        assert_eq!(render("{title}{title}", &[("title", "a")]), "aa");
+        assert_eq!(render("!{iid}", &[]), "!");
+        assert_eq!(render("", &[]), "");
+    }
+
+    #[test]
+    fn render_escapes() {
+        assert_eq!(render("{{title}}", &[("title", "Fix")]), "{title}");
+        assert_eq!(render("{{{title}}}", &[("title", "Fix")]), "{Fix}");
+        assert_eq!(render("fn f() {{ }}", &[]), "fn f() { }");
+    }
+
+    #[test]
+    fn parse_errors() {
+        assert!(Template::parse("{author}", KEYS).is_err());
+        assert!(Template::parse("{Title}", KEYS).is_err());
+        assert!(Template::parse("{}", KEYS).is_err());
+        assert!(Template::parse("{title", KEYS).is_err());
+        assert!(Template::parse("title}", KEYS).is_err());
+    }
+}
@@ -0,0 +1,280 @@
+use errors::*;
+use merge_request::MERGE_BRANCH_PREFIX;
+use serde_json;
+
+/// Queue evaluation request derived from a GitLab webhook event.
+///
+/// `target_branch` is `None` when the affected target branch cannot be determined from the
+/// payload, in which case every queue of the project is evaluated.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Trigger {
+    pub project: String,
+    pub target_branch: Option<String>,
+    /// The event may come from the source project (possibly a fork) of merge requests
+    pub from_source: bool,
+}
+
+#[derive(Deserialize)]
+struct HookProject {
+    path_with_namespace: String,
+}
+
+#[derive(Deserialize)]
+struct NoteHook {
+    project: HookProject,
+    merge_request: Option<HookMergeRequest>,
+}
+
+#[derive(Deserialize)]
+struct MergeRequestHook {
+    project: HookProject,
+    object_attributes: HookMergeRequest,
+}
+
+#[derive(Deserialize)]
+struct HookMergeRequest {
+    target_branch: String,
+}
+
+#[derive(Deserialize)]
+struct PipelineHook {
+    project: HookProject,
+    object_attributes: HookPipeline,
+}
+
+#[derive(Deserialize)]
+struct HookPipeline {
+    #[serde(rename = "ref")]
+    ref_: String,
+}
+
+#[derive(Deserialize)]
+struct PushHook {
+    project: HookProject,
+}
+
+/// Parses a webhook payload. `event` is the value of the `X-Gitlab-Event` header.
+///
+/// Returns `None` for events jaba is not interested in.
+pub fn parse(event: &str, body: &str) -> Result<Option<Trigger>> {
+    let trigger = match event {
+        "Note Hook" => {
+            let hook: NoteHook = serde_json::from_str(body)?;
+            Trigger {
+                project: hook.project.path_with_namespace,
+                target_branch: hook.merge_request.map(|mr| mr.target_branch),
+                from_source: false,
+            }
+        }
+        "Merge Request Hook" => {
+            let hook: MergeRequestHook = serde_json::from_str(body)?;
+            Trigger {
+                project: hook.project.path_with_namespace,
+                target_branch: Some(hook.object_attributes.target_branch),
+                from_source: false,
+            }
+        }
+        "Pipeline Hook" => {
+            let hook: PipelineHook = serde_json::from_str(body)?;
+            // Pipelines on source branches affect merge requests targeting any branch
+            let target_branch = if hook.object_attributes.ref_.starts_with(MERGE_BRANCH_PREFIX) {
+                Some(hook.object_attributes.ref_[MERGE_BRANCH_PREFIX.len()..].to_string())
+            } else {
+                None
+            };
+            Trigger {
+                project: hook.project.path_with_namespace,
+                target_branch: target_branch,
+                from_source: true,
+            }
+        }
+        "Push Hook" => {
+            // The pushed branch may be a target branch or a source branch of any merge request
+            let hook: PushHook = serde_json::from_str(body)?;
+            Trigger {
+                project: hook.project.path_with_namespace,
+                target_branch: None,
+                from_source: true,
+            }
+        }
+        _ => return Ok(None),
+    };
+
+    Ok(Some(trigger))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOTE_HOOK: &'static str = r#"{
+        "object_kind": "note",
+        "user": {"name": "Administrator", "username": "root"},
+        "project_id": 5,
+        "project": {
+            "name": "Gitlab Test",
+            "path_with_namespace": "gitlabhq/gitlab-test",
+            "default_branch": "master"
+        },
+        "object_attributes": {
+            "id": 1244,
+            "note": "@jaba r+",
+            "noteable_type": "MergeRequest",
+            "noteable_id": 7
+        },
+        "merge_request": {
+            "id": 7,
+            "iid": 1,
+            "target_branch": "markdown",
+            "source_branch": "master",
+            "source_project_id": 5,
+            "target_project_id": 5,
+            "state": "opened"
+        }
+    }"#;
+
+    const COMMIT_NOTE_HOOK: &'static str = r#"{
+        "object_kind": "note",
+        "project_id": 5,
+        "project": {"path_with_namespace": "gitlabhq/gitlab-test"},
+        "object_attributes": {"id": 1243, "note": "nice", "noteable_type": "Commit"},
+        "commit": {"id": "cfe32cf61b73a0d5e9f13e774abde7ff789b1660"}
+    }"#;
+
+    const FORK_MERGE_REQUEST_HOOK: &'static str = r#"{
+        "object_kind": "merge_request",
+        "user": {"name": "Administrator", "username": "root"},
+        "project": {
+            "name": "Gitlab Test",
+            "path_with_namespace": "gitlabhq/gitlab-test",
+            "default_branch": "master"
+        },
+        "object_attributes": {
+            "id": 99,
+            "iid": 1,
+            "target_branch": "master",
+            "source_branch": "ms-viewport",
+            "source_project_id": 14,
+            "target_project_id": 5,
+            "state": "opened",
+            "action": "open"
+        }
+    }"#;
+
+    const MERGE_PIPELINE_HOOK: &'static str = r#"{
+        "object_kind": "pipeline",
+        "object_attributes": {
+            "id": 31,
+            "ref": "auto-master",
+            "tag": false,
+            "sha": "bcbb5ec396a2c0f828686f14fac9b80b780504f2",
+            "status": "success"
+        },
+        "project": {
+            "name": "Gitlab Test",
+            "path_with_namespace": "gitlabhq/gitlab-test",
+            "default_branch": "master"
+        },
+        "builds": []
+    }"#;
+
+    const FORK_PIPELINE_HOOK: &'static str = r#"{
+        "object_kind": "pipeline",
+        "object_attributes": {
+            "id": 32,
+            "ref": "ms-viewport",
+            "tag": false,
+            "sha": "b83d6e391c22777fca1ed3012fce84f633d7fed0",
+            "status": "failed"
+        },
+        "project": {
+            "name": "Gitlab Test",
+            "path_with_namespace": "contributor/gitlab-test",
+            "default_branch": "master"
+        },
+        "builds": []
+    }"#;
+
+    const PUSH_HOOK: &'static str = r#"{
+        "object_kind": "push",
+        "before": "95790bf891e76fee5e1747ab589903a6a1f80f22",
+        "after": "da1560886d4f094c3e6c9ef40349f7d38b5d27d7",
+        "ref": "refs/heads/master",
+        "user_name": "John Smith",
+        "project_id": 15,
+        "project": {
+            "name": "Diaspora",
+            "path_with_namespace": "mike/diaspora",
+            "default_branch": "master"
+        },
+        "commits": [],
+        "total_commits_count": 0
+    }"#;
+
+    #[test]
+    fn parse_note_hook() {
+        assert_eq!(parse("Note Hook", NOTE_HOOK).unwrap(),
+                   Some(Trigger {
+                       project: "gitlabhq/gitlab-test".into(),
+                       target_branch: Some("markdown".into()),
+                       from_source: false,
+                   }));
+        assert_eq!(parse("Note Hook", COMMIT_NOTE_HOOK).unwrap(),
+                   Some(Trigger {
+                       project: "gitlabhq/gitlab-test".into(),
+                       target_branch: None,
+                       from_source: false,
+                   }));
+    }
+
+    #[test]
+    fn parse_merge_request_hook() {
+        // Merge request hooks are sent to the target project, even for merge requests from forks
+        assert_eq!(parse("Merge Request Hook", FORK_MERGE_REQUEST_HOOK).unwrap(),
+                   Some(Trigger {
+                       project: "gitlabhq/gitlab-test".into(),
+                       target_branch: Some("master".into()),
+                       from_source: false,
+                   }));
+    }
+
+    #[test]
+    fn parse_pipeline_hook() {
+        assert_eq!(parse("Pipeline Hook", MERGE_PIPELINE_HOOK).unwrap(),
+                   Some(Trigger {
+                       project: "gitlabhq/gitlab-test".into(),
+                       target_branch: Some("master".into()),
+                       from_source: true,
+                   }));
+        assert_eq!(parse("Pipeline Hook", FORK_PIPELINE_HOOK).unwrap(),
+                   Some(Trigger {
+                       project: "contributor/gitlab-test".into(),
+                       target_branch: None,
+                       from_source: true,
+                   }));
+    }
+
+    #[test]
+    fn parse_push_hook() {
+        assert_eq!(parse("Push Hook", PUSH_HOOK).unwrap(),
+                   Some(Trigger {
+                       project: "mike/diaspora".into(),
+                       target_branch: None,
+                       from_source: true,
+                   }));
+    }
+
+    #[test]
+    fn parse_unknown_event() {
+        assert_eq!(parse("Tag Push Hook", PUSH_HOOK).unwrap(), None);
+        assert_eq!(parse("Wiki Page Hook", "not json").unwrap(), None);
+    }
+
+    #[test]
+    fn parse_malformed_body() {
+        assert!(parse("Note Hook", "").is_err());
+        assert!(parse("Push Hook", "{\"project\": 1}").is_err());
+        assert!(parse("Merge Request Hook", NOTE_HOOK).is_err());
+        assert!(parse("Pipeline Hook", "{\"object_kind\": \"pipeline\"").is_err());
+    }
+}